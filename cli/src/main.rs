@@ -0,0 +1,134 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use foreverhash::*;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+#[derive(Parser, Debug)]
+struct CommandArgs {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check all the pages of a table without modifying it.
+    Verify(TableArgs),
+    /// Copy the pairs still readable from a damaged table into a new table.
+    Repair(RepairArgs),
+}
+
+#[derive(Args, Debug)]
+struct RepairArgs {
+    #[command(flatten)]
+    src: TableArgs,
+    #[arg(long)]
+    dst_main: PathBuf,
+    #[arg(long)]
+    dst_overflow: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct TableArgs {
+    #[arg(long)]
+    main: PathBuf,
+    #[arg(long)]
+    overflow: PathBuf,
+    #[arg(long, default_value_t = 4096)]
+    page_size: u32,
+    /// The hasher the table was created with. The default of the library if not given.
+    #[arg(long)]
+    hasher: Option<HasherKind>,
+    /// The seed of siphash13.
+    #[arg(long, default_value_t = 0)]
+    k0: u64,
+    #[arg(long, default_value_t = 0)]
+    k1: u64,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum HasherKind {
+    Identity,
+    Xxh3,
+    Siphash13,
+}
+
+impl TableArgs {
+    fn options(&self) -> Options {
+        let mut options = Options {
+            page_size: self.page_size,
+            ..Default::default()
+        };
+        if let Some(hasher) = self.hasher {
+            options.hasher = match hasher {
+                HasherKind::Identity => Arc::new(IdentityU64),
+                HasherKind::Xxh3 => Arc::new(Xxh3),
+                HasherKind::Siphash13 => Arc::new(SipHash13 {
+                    k0: self.k0,
+                    k1: self.k1,
+                }),
+            };
+        }
+        options
+    }
+}
+
+fn verify(args: &TableArgs) -> Result<bool, Error> {
+    let report = ForeverHash::verify(&args.main, &args.overflow, args.options())?;
+
+    println!("clean: {}", report.clean);
+    println!("main pages: {}", report.n_main_pages);
+    println!(
+        "overflow pages: {} (used {}, free {}, unreferenced {})",
+        report.n_overflow_pages,
+        report.n_used_overflow_pages,
+        report.n_free_overflow_pages,
+        report.n_unreferenced_overflow_pages,
+    );
+    println!("items: {}", report.n_items);
+    println!("bytes: {}", report.n_bytes);
+    println!("problems: {}", report.problems.len());
+    for problem in &report.problems {
+        println!("  {problem:?}");
+    }
+
+    Ok(report.is_ok())
+}
+
+fn repair(args: &RepairArgs) -> Result<bool, Error> {
+    let report = ForeverHash::repair(
+        &args.src.main,
+        &args.src.overflow,
+        &args.dst_main,
+        &args.dst_overflow,
+        args.src.options(),
+    )?;
+
+    println!("items: {}", report.n_items);
+    println!("orphaned items: {}", report.n_orphaned_items);
+    println!("duplicates: {}", report.n_duplicates);
+    println!("invalid keys: {}", report.n_invalid_keys);
+    println!("lost: {}", report.lost.len());
+    for problem in &report.lost {
+        println!("  {problem:?}");
+    }
+
+    Ok(report.lost.is_empty() && report.n_invalid_keys == 0)
+}
+
+fn main() -> ExitCode {
+    let args = CommandArgs::parse();
+
+    let r = match &args.command {
+        Command::Verify(args) => verify(args),
+        Command::Repair(args) => repair(args),
+    };
+    match r {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::from(2)
+        }
+    }
+}
@@ -1,5 +1,7 @@
+#[allow(unused_imports)]
 use std::io::Write;
 use std::os::unix::fs::FileExt;
+use std::time::Instant;
 
 use super::*;
 
@@ -9,10 +11,20 @@ const HEADER_LEN: u32 = 4 + 4;
 pub struct DataLog {
     f: std::fs::File,
     cursor: u64,
+    sync_policy: SyncPolicy,
+    last_sync: Instant,
 }
 
 impl DataLog {
+    /// Open the log which is never synced until `sync` is called.
     pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with(path, SyncPolicy::Never)
+    }
+
+    /// There are no splits in the log, so `SyncPolicy::OnSplit` never syncs like `Never`.
+    pub fn open_with(path: &Path, sync_policy: SyncPolicy) -> Result<Self> {
+        // The log is appended to, so an existing file is kept as is.
+        #[allow(clippy::suspicious_open_options)]
         let f = std::fs::OpenOptions::new()
             .write(true)
             .read(true)
@@ -23,7 +35,12 @@ impl DataLog {
         let meta = f.metadata()?;
         let cursor = meta.len();
 
-        Ok(Self { f, cursor })
+        Ok(Self {
+            f,
+            cursor,
+            sync_policy,
+            last_sync: Instant::now(),
+        })
     }
 
     // Appends data to the log and returns the offset where the data was written.
@@ -44,9 +61,25 @@ impl DataLog {
         self.f.write_at(&buf, offset)?;
         self.cursor += HEADER_LEN as u64 + data_len as u64;
 
+        if self.sync_policy.is_due(self.last_sync) {
+            self.sync()?;
+        }
+
         Ok((offset, HEADER_LEN + data_len))
     }
 
+    /// The bytes written to the log, including the headers of the records.
+    pub fn size(&self) -> u64 {
+        self.cursor
+    }
+
+    /// Persist all the data appended so far.
+    pub fn sync(&mut self) -> Result<()> {
+        self.f.sync_data()?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
     pub(super) fn read(&self, k: (u64, u32)) -> Result<Vec<u8>> {
         let (offset, len) = k;
         let mut buf = vec![0u8; len as usize];
@@ -11,12 +11,42 @@ pub struct DBIndex {
 }
 
 impl DBIndex {
+    /// The filter length used by `open`.
+    /// `exists` is often asked for missing keys. The filters answer it from the main page.
+    pub const DEFAULT_FILTER_LEN: u32 = 128;
+
     pub fn open(main: &Path, overflow: &Path) -> Result<Self> {
-        let db = foreverhash::ForeverHash::open(main, overflow)?;
+        Self::open_with(
+            main,
+            overflow,
+            SyncPolicy::default(),
+            Self::DEFAULT_FILTER_LEN,
+        )
+    }
+
+    /// `filter_len` is the bytes of the filter in each main page, or 0 for no filter.
+    pub fn open_with(
+        main: &Path,
+        overflow: &Path,
+        sync_policy: SyncPolicy,
+        filter_len: u32,
+    ) -> Result<Self> {
+        let options = foreverhash::Options {
+            sync_policy,
+            filter_len,
+            ..Default::default()
+        };
+        let db = foreverhash::ForeverHash::open_with(main, overflow, options)?;
 
         Ok(Self { db })
     }
 
+    /// Persist all the index entries inserted so far.
+    pub fn sync(&mut self) -> Result<()> {
+        self.db.sync()?;
+        Ok(())
+    }
+
     pub(super) fn insert(&mut self, k: Vec<u8>, e: IndexEntry) -> Result<()> {
         let v = rkyv::to_bytes::<rkyv::rancor::Error>(&e).unwrap();
         self.db.insert(k, v.into_vec())?;
@@ -27,9 +57,39 @@ impl DBIndex {
         let Some(data) = self.db.get(k)? else {
             return Ok(None);
         };
-        let v = rkyv::from_bytes::<IndexEntry, rkyv::rancor::Error>(&data).unwrap();
+        let v = rkyv::from_bytes::<IndexEntry, rkyv::rancor::Error>(&data)
+            .map_err(|_| Error::InvalidIndexEntry)?;
         Ok(Some(v))
     }
+
+    pub fn stats(&self) -> Result<foreverhash::TableStats> {
+        Ok(self.db.stats()?)
+    }
+
+    /// The total length of the log records the index points to.
+    pub(super) fn live_bytes(&self) -> Result<u64> {
+        let mut n = 0;
+        for v in self.db.values() {
+            let e = rkyv::from_bytes::<IndexEntry, rkyv::rancor::Error>(&v?)
+                .map_err(|_| Error::InvalidIndexEntry)?;
+            n += e.data_len as u64;
+        }
+        Ok(n)
+    }
+
+    pub(super) fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<IndexEntry>>> {
+        self.db
+            .get_many(keys)?
+            .into_iter()
+            .map(|data| {
+                data.map(|data| {
+                    rkyv::from_bytes::<IndexEntry, rkyv::rancor::Error>(&data)
+                        .map_err(|_| Error::InvalidIndexEntry)
+                })
+                .transpose()
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -1,3 +1,4 @@
+#[allow(unused_imports)]
 use super::*;
 
 #[derive(thiserror::Error, Debug)]
@@ -6,10 +7,23 @@ pub enum Error {
     LogMagicMismatch,
     #[error("Log CRC mismatch")]
     LogCrcMismatch,
+    #[error("Invalid index entry")]
+    InvalidIndexEntry,
     #[error(transparent)]
     IO(#[from] std::io::Error),
     #[error(transparent)]
     HashTable(#[from] foreverhash::Error),
 }
 
+impl Error {
+    /// True if the data log or the index is corrupted.
+    pub fn is_corruption(&self) -> bool {
+        match self {
+            Error::LogMagicMismatch | Error::LogCrcMismatch | Error::InvalidIndexEntry => true,
+            Error::HashTable(e) => e.is_corruption(),
+            Error::IO(_) => false,
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
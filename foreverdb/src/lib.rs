@@ -3,12 +3,26 @@ pub use error::Error;
 use error::Result;
 use std::path::Path;
 
+pub use foreverhash::SyncPolicy;
+
 mod data_log;
 pub use data_log::DataLog;
 mod db_index;
 pub use db_index::DBIndex;
 use db_index::IndexEntry;
 
+/// The result of `ForeverDB::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DBStats {
+    pub index: foreverhash::TableStats,
+    /// The size of the data log.
+    pub log_size: u64,
+    /// The bytes of the records the index points to.
+    pub live_bytes: u64,
+    /// The bytes of the records overwritten by later inserts.
+    pub dead_bytes: u64,
+}
+
 pub struct ForeverDB {
     data_log: DataLog,
     db_index: DBIndex,
@@ -33,6 +47,12 @@ impl ForeverDB {
         Ok(())
     }
 
+    /// Persist all the inserts so far. The data is synced before the index pointing to it.
+    pub fn sync(&mut self) -> Result<()> {
+        self.data_log.sync()?;
+        self.db_index.sync()
+    }
+
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         let Some(e) = self.db_index.get(key)? else {
             return Ok(None);
@@ -41,6 +61,38 @@ impl ForeverDB {
         Ok(Some(self.data_log.read((e.data_offset, e.data_len))?))
     }
 
+    /// Look up many keys at once. The index is read bucket by bucket
+    /// and the data in the order of the log. The values are returned in the order of the keys.
+    pub fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut entries: Vec<(usize, IndexEntry)> = self
+            .db_index
+            .get_many(keys)?
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, e)| Some((i, e?)))
+            .collect();
+        entries.sort_by_key(|(_, e)| e.data_offset);
+
+        let mut values = vec![None; keys.len()];
+        for (i, e) in entries {
+            values[i] = Some(self.data_log.read((e.data_offset, e.data_len))?);
+        }
+        Ok(values)
+    }
+
+    /// Report the shape of the index and how much of the log is still referenced.
+    /// It reads the whole index.
+    pub fn stats(&self) -> Result<DBStats> {
+        let log_size = self.data_log.size();
+        let live_bytes = self.db_index.live_bytes()?;
+        Ok(DBStats {
+            index: self.db_index.stats()?,
+            log_size,
+            live_bytes,
+            dead_bytes: log_size.saturating_sub(live_bytes),
+        })
+    }
+
     pub fn exists(&self, key: &[u8]) -> Result<bool> {
         Ok(self.db_index.get(key)?.is_some())
     }
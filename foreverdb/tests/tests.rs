@@ -24,3 +24,85 @@ fn test_insert_and_get() {
     assert_eq!(db.get(&k1).unwrap().unwrap(), v1);
     assert_eq!(db.get(&k2).unwrap().unwrap(), v2);
 }
+
+#[test]
+fn test_sync() {
+    let log_file = tempfile::NamedTempFile::new().unwrap();
+    let main_file = tempfile::NamedTempFile::new().unwrap();
+    let overflow_file = tempfile::NamedTempFile::new().unwrap();
+
+    let policy = SyncPolicy::EveryWrite;
+    let data_log = DataLog::open_with(log_file.path(), policy).unwrap();
+    let db_index = DBIndex::open_with(
+        main_file.path(),
+        overflow_file.path(),
+        policy,
+        DBIndex::DEFAULT_FILTER_LEN,
+    )
+    .unwrap();
+    let mut db = ForeverDB::new(data_log, db_index);
+
+    for i in 0..100u8 {
+        db.insert(vec![i; 32], vec![i; 100]).unwrap();
+    }
+    db.sync().unwrap();
+
+    for i in 0..100u8 {
+        assert_eq!(db.get(&[i; 32]).unwrap().unwrap(), vec![i; 100]);
+    }
+}
+
+#[test]
+fn test_get_many() {
+    let log_file = tempfile::NamedTempFile::new().unwrap();
+    let main_file = tempfile::NamedTempFile::new().unwrap();
+    let overflow_file = tempfile::NamedTempFile::new().unwrap();
+
+    let data_log = DataLog::open(log_file.path()).unwrap();
+    let db_index = DBIndex::open(main_file.path(), overflow_file.path()).unwrap();
+    let mut db = ForeverDB::new(data_log, db_index);
+
+    for i in 0..100u8 {
+        db.insert(vec![i; 32], vec![i; 100]).unwrap();
+    }
+    // The latest data wins.
+    db.insert(vec![7; 32], vec![0; 10]).unwrap();
+
+    let keys: Vec<Vec<u8>> = (0..120u8).rev().map(|i| vec![i; 32]).collect();
+    let values = db.get_many(&keys).unwrap();
+    for (k, v) in keys.iter().zip(values) {
+        let i = k[0];
+        let expected = match i {
+            7 => Some(vec![0; 10]),
+            0..100 => Some(vec![i; 100]),
+            _ => None,
+        };
+        assert_eq!(v, expected);
+    }
+    assert!(db.get_many::<&[u8]>(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn test_stats() {
+    let log_file = tempfile::NamedTempFile::new().unwrap();
+    let main_file = tempfile::NamedTempFile::new().unwrap();
+    let overflow_file = tempfile::NamedTempFile::new().unwrap();
+
+    let data_log = DataLog::open(log_file.path()).unwrap();
+    let db_index = DBIndex::open(main_file.path(), overflow_file.path()).unwrap();
+    let mut db = ForeverDB::new(data_log, db_index);
+
+    for i in 0..100u8 {
+        db.insert(vec![i; 32], vec![i; 100]).unwrap();
+    }
+    // Overwrite half of the keys.
+    for i in 0..50u8 {
+        db.insert(vec![i; 32], vec![i; 100]).unwrap();
+    }
+
+    let stats = db.stats().unwrap();
+    assert_eq!(stats.index.n_items, 100);
+    assert_eq!(stats.log_size, 150 * 108);
+    assert_eq!(stats.live_bytes, 100 * 108);
+    assert_eq!(stats.dead_bytes, 50 * 108);
+}
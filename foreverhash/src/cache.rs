@@ -0,0 +1,220 @@
+use super::*;
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The number of clock sweeps the page survives without being accessed.
+/// Main pages are read on every lookup so they are kept longer than overflow pages.
+fn weight(file: PageFile) -> u8 {
+    match file {
+        PageFile::Main => 2,
+        PageFile::Overflow => 1,
+    }
+}
+
+/// The current version of a page.
+#[derive(Clone)]
+pub struct Frame {
+    pub buf: PageBuf,
+    pub data_range: Range<usize>,
+    /// The sequence number and the slot holding the page.
+    /// The next write goes to the other slot with the next number.
+    pub seq: u64,
+    pub slot: u64,
+}
+
+impl Frame {
+    pub fn new(data: &[u8], seq: u64, slot: u64) -> Self {
+        let mut buf = AlignedVec::with_capacity(data.len());
+        buf.extend_from_slice(data);
+        Self {
+            buf: PageBuf::Heap(Arc::new(buf)),
+            data_range: 0..data.len(),
+            seq,
+            slot,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buf[self.data_range.clone()]
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub used_bytes: u64,
+}
+
+type Key = (PageFile, u64);
+
+struct Entry {
+    key: Key,
+    frame: Frame,
+    referenced: u8,
+}
+
+#[derive(Default)]
+struct Inner {
+    map: HashMap<Key, usize>,
+    entries: Vec<Option<Entry>>,
+    free: Vec<usize>,
+    hand: usize,
+    used_bytes: usize,
+}
+
+impl Inner {
+    fn remove(&mut self, i: usize) {
+        let e = self.entries[i].take().unwrap();
+        self.map.remove(&e.key);
+        self.used_bytes -= e.frame.data_range.len();
+        self.free.push(i);
+    }
+
+    /// Evict a page by the CLOCK algorithm.
+    fn evict_one(&mut self) {
+        loop {
+            if self.hand >= self.entries.len() {
+                self.hand = 0;
+            }
+            let i = self.hand;
+            self.hand += 1;
+
+            let Some(e) = &mut self.entries[i] else {
+                continue;
+            };
+            if e.referenced > 0 {
+                e.referenced -= 1;
+            } else {
+                self.remove(i);
+                return;
+            }
+        }
+    }
+}
+
+/// Page cache shared by the main and overflow devices. The capacity is in bytes.
+/// Writes go through the cache so the cached pages are always up to date.
+pub struct PageCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Inner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, file: PageFile, id: u64) -> Option<Frame> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(&i) = inner.map.get(&(file, id)) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+
+        let e = inner.entries[i].as_mut().unwrap();
+        e.referenced = weight(file);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(e.frame.clone())
+    }
+
+    pub fn insert(&self, file: PageFile, id: u64, frame: Frame) {
+        let size = frame.data_range.len();
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(&i) = inner.map.get(&(file, id)) {
+            inner.remove(i);
+        }
+        if size > self.capacity {
+            return;
+        }
+
+        while inner.used_bytes + size > self.capacity {
+            inner.evict_one();
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let e = Entry {
+            key: (file, id),
+            frame,
+            referenced: weight(file),
+        };
+        let i = match inner.free.pop() {
+            Some(i) => {
+                inner.entries[i] = Some(e);
+                i
+            }
+            None => {
+                inner.entries.push(Some(e));
+                inner.entries.len() - 1
+            }
+        };
+        inner.map.insert((file, id), i);
+        inner.used_bytes += size;
+    }
+
+    /// Drop the pages from `id` in the file.
+    pub fn remove_from(&self, file: PageFile, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let targets: Vec<usize> = inner
+            .map
+            .iter()
+            .filter(|((s, i), _)| *s == file && *i >= id)
+            .map(|(_, &i)| i)
+            .collect();
+        for i in targets {
+            inner.remove(i);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            used_bytes: self.inner.lock().unwrap().used_bytes as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_main_pages_stay_hot() {
+        let cache = PageCache::new(4 * 100);
+        let frame = Frame::new(&[0; 100], 0, 0);
+
+        cache.insert(PageFile::Main, 0, frame.clone());
+        for id in 0..6 {
+            cache.insert(PageFile::Overflow, id, frame.clone());
+        }
+
+        // The main page is older but outlives the overflow pages.
+        assert!(cache.get(PageFile::Main, 0).is_some());
+        assert!(cache.get(PageFile::Overflow, 0).is_none());
+        assert!(cache.get(PageFile::Overflow, 5).is_some());
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.evictions, 3);
+        assert_eq!(stats.used_bytes, 400);
+
+        cache.remove_from(PageFile::Overflow, 5);
+        assert!(cache.get(PageFile::Overflow, 5).is_none());
+        assert!(cache.get(PageFile::Main, 0).is_some());
+    }
+}
@@ -1,103 +1,585 @@
 use super::*;
 
+use std::sync::{Mutex, Weak};
+
+struct MapState {
+    mapping: Arc<Mapping>,
+    file_len: u64,
+}
+
 struct IO {
-    f: File,
+    store: Box<dyn BlockIo>,
+    /// If set, pages are read from the mapping without copying.
+    map: Option<Mutex<MapState>>,
 }
 
 impl IO {
-    fn new(f: File) -> Self {
-        Self { f }
+    fn new(store: Box<dyn BlockIo>) -> Self {
+        Self { store, map: None }
+    }
+
+    fn new_mmap(store: Box<dyn BlockIo>) -> Result<Self> {
+        let Some(f) = store.as_file() else {
+            return Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into());
+        };
+        let file_len = store.len()?;
+        let mapping = Mapping::new(f, mmap::mapping_len(file_len))?;
+        let map = MapState {
+            mapping: Arc::new(mapping),
+            file_len,
+        };
+        Ok(Self {
+            store,
+            map: Some(Mutex::new(map)),
+        })
     }
 
     fn read(&self, buf: &mut [u8], offset: u64) -> Result<()> {
-        self.f.read_at(buf, offset)?;
+        let Some(map) = &self.map else {
+            self.store.read_at(buf, offset)?;
+            return Ok(());
+        };
+
+        let map = map.lock().unwrap();
+        let start = offset.min(map.file_len) as usize;
+        let end = (offset + buf.len() as u64).min(map.file_len) as usize;
+        let n = end - start;
+        buf[..n].copy_from_slice(&map.mapping[start..end]);
+        buf[n..].fill(0);
         Ok(())
     }
 
+    /// Returns the mapping if the range is in the file.
+    fn view(&self, offset: u64, len: usize) -> Option<Arc<Mapping>> {
+        let map = self.map.as_ref()?.lock().unwrap();
+        if offset + len as u64 > map.file_len {
+            return None;
+        }
+        Some(map.mapping.clone())
+    }
+
     fn write(&self, buf: &[u8], offset: u64) -> Result<()> {
-        self.f.write_at(buf, offset)?;
+        self.store.write_at(buf, offset)?;
+
+        if let Some(map) = &self.map {
+            let mut map = map.lock().unwrap();
+            let end = offset + buf.len() as u64;
+            if end > map.file_len {
+                self.remap(&mut map, end)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remap(&self, map: &mut MapState, file_len: u64) -> Result<()> {
+        map.file_len = file_len;
+        // The file grew beyond the mapping. Readers holding the old mapping can still use it
+        // for the range in the file. Reading a range truncated away raises SIGBUS in any mapping.
+        if file_len as usize > map.mapping.len() {
+            let f = self.store.as_file().unwrap();
+            let mapping = Mapping::new(f, mmap::mapping_len(file_len))?;
+            map.mapping = Arc::new(mapping);
+        }
         Ok(())
     }
 
     fn flush(&self) -> Result<()> {
-        self.f.sync_all()?;
+        match &self.map {
+            Some(map) => {
+                let map = map.lock().unwrap();
+                map.mapping.sync(map.file_len as usize)?;
+            }
+            None => {
+                self.store.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Extend the file to `len` if it is shorter.
+    fn grow(&self, len: u64) -> Result<()> {
+        if self.store.len()? < len {
+            self.truncate(len)?;
+        }
         Ok(())
     }
+
+    /// When shrinking, no reader may still hold a view of the dropped range.
+    fn truncate(&self, len: u64) -> Result<()> {
+        let Some(map) = &self.map else {
+            self.store.set_len(len)?;
+            return Ok(());
+        };
+        // Holding the lock, no new view of the dropped range is handed out while the file shrinks.
+        let mut map = map.lock().unwrap();
+        self.store.set_len(len)?;
+        self.remap(&mut map, len)
+    }
+}
+
+/// The header of a page: crc (4 bytes), data length (4 bytes) and sequence number (8 bytes).
+/// The crc covers the sequence number and the data.
+const PAGE_HEADER_LEN: usize = 16;
+
+/// Each page has two slots and updates go to the slot not holding the current page.
+const N_SLOTS: u64 = 2;
+
+enum Slot {
+    /// Never written.
+    Empty,
+    /// The crc doesn't match. The write was torn.
+    Torn,
+    Valid {
+        seq: u64,
+        data_range: Range<usize>,
+    },
+}
+
+fn parse_slot(buf: &[u8]) -> Slot {
+    let stored_crc = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+    let data_len = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+    if data_len == 0 {
+        return Slot::Empty;
+    }
+    if data_len > buf.len() - PAGE_HEADER_LEN {
+        return Slot::Torn;
+    }
+
+    let data_range = PAGE_HEADER_LEN..(PAGE_HEADER_LEN + data_len);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&buf[8..16]);
+    hasher.update(&buf[data_range.clone()]);
+    if stored_crc != hasher.finalize() {
+        return Slot::Torn;
+    }
+
+    let seq = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+    Slot::Valid { seq, data_range }
+}
+
+/// The pages written since the flush which made them durable.
+/// Until the next flush, a page is rewritten in the slot written last
+/// so the other slot keeps the durable version even if the rewrites are lost or torn.
+#[derive(Default)]
+struct Unflushed {
+    /// Incremented when a flush starts.
+    epoch: u64,
+    /// The slot written and the epoch of the last write of each page.
+    pages: HashMap<u64, (u64, u64)>,
 }
 
 pub struct Device {
     io: IO,
+    page_size: usize,
+    /// The number of pages reserved at the head of the file.
+    header_pages: u64,
+    file: PageFile,
+    cache: Option<Arc<PageCache>>,
+    /// If true, the data is validated before zero-copy access.
+    checked: bool,
+    /// If false, `flush` does nothing and only `sync` reaches the disk.
+    ordered: bool,
+    /// The live snapshots to save the pages into before they are overwritten.
+    snapshots: Mutex<Vec<Weak<Preserved>>>,
+    unflushed: Mutex<Unflushed>,
 }
 
 impl Device {
-    pub fn new(f: File) -> Self {
-        Self { io: IO::new(f) }
+    /// Device for main pages.
+    /// The first page of the file is reserved for the header and the slots of page 0 come after it.
+    pub fn new_main(store: Box<dyn BlockIo>, page_size: usize) -> Self {
+        Self {
+            io: IO::new(store),
+            page_size,
+            header_pages: 1,
+            file: PageFile::Main,
+            cache: None,
+            checked: false,
+            ordered: true,
+            snapshots: Mutex::new(Vec::new()),
+            unflushed: Mutex::new(Unflushed::default()),
+        }
     }
 
-    fn into_data(page: Page) -> Vec<u8> {
+    /// Device for overflow pages.
+    /// Overflow pages are updated in place in the chain, so they also have two slots like main pages.
+    pub fn new_overflow(store: Box<dyn BlockIo>, page_size: usize) -> Self {
+        Self {
+            io: IO::new(store),
+            page_size,
+            header_pages: 0,
+            file: PageFile::Overflow,
+            cache: None,
+            checked: false,
+            ordered: true,
+            snapshots: Mutex::new(Vec::new()),
+            unflushed: Mutex::new(Unflushed::default()),
+        }
+    }
+
+    pub fn with_cache(mut self, cache: Option<Arc<PageCache>>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn with_checked(mut self, checked: bool) -> Self {
+        self.checked = checked;
+        self
+    }
+
+    pub fn with_ordered(mut self, ordered: bool) -> Self {
+        self.ordered = ordered;
+        self
+    }
+
+    /// Read the pages from the mapping of the file. Fails if the store isn't on a file.
+    pub fn with_mmap(mut self) -> Result<Self> {
+        self.io = IO::new_mmap(self.io.store)?;
+        Ok(self)
+    }
+
+    fn offset(&self, id: u64) -> u64 {
+        (self.header_pages + id * N_SLOTS) * self.page_size as u64
+    }
+
+    /// The maximum length of the encoded page.
+    pub fn max_data_len(&self) -> usize {
+        self.page_size - PAGE_HEADER_LEN
+    }
+
+    fn encode(&self, page: Page, seq: u64) -> Vec<u8> {
         let data = encode_page(&page);
-        assert!(data.len() <= 4088);
+        assert!(data.len() <= self.max_data_len());
 
-        let crc = crc32fast::hash(&data);
         let data_len = data.len() as u32;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&seq.to_le_bytes());
+        hasher.update(&data);
+        let crc = hasher.finalize();
 
-        let mut out = Vec::with_capacity(8 + data.len());
+        let mut out = Vec::with_capacity(PAGE_HEADER_LEN + data.len());
         out.extend_from_slice(&crc.to_le_bytes());
         out.extend_from_slice(&data_len.to_le_bytes());
+        out.extend_from_slice(&seq.to_le_bytes());
         out.extend_from_slice(&data);
 
         out
     }
 
-    pub fn write_page(&self, id: u64, page: Page) -> Result<()> {
-        let buf = Self::into_data(page);
-        self.io.write(&buf, id * 4096)?;
+    /// Reads all the slots of the page and returns the current page.
+    fn read_slots(&self, id: u64) -> Result<Option<Frame>> {
+        let len = self.page_size * N_SLOTS as usize;
+        let offset = self.offset(id);
+        // The position of the slots in the buffer.
+        let (buf, pos) = match self.io.view(offset, len) {
+            Some(mapping) => (PageBuf::Mapped(mapping), offset as usize),
+            None => {
+                let mut buf = AlignedVec::with_capacity(len);
+                buf.resize(len, 0);
+                self.io.read(&mut buf, offset)?;
+                (PageBuf::Heap(Arc::new(buf)), 0)
+            }
+        };
+
+        let mut cur: Option<(u64, u64, Range<usize>)> = None;
+        for i in 0..N_SLOTS as usize {
+            let base = pos + i * self.page_size;
+            match parse_slot(&buf[base..(base + self.page_size)]) {
+                Slot::Empty => {}
+                // A torn slot is the update which didn't complete. The other slot holds the page.
+                Slot::Torn => {}
+                Slot::Valid { seq, data_range } => {
+                    if cur.as_ref().is_none_or(|(cur_seq, _, _)| seq > *cur_seq) {
+                        let data_range = (base + data_range.start)..(base + data_range.end);
+                        cur = Some((seq, i as u64, data_range));
+                    }
+                }
+            }
+        }
+
+        let frame = cur.map(|(seq, slot, data_range)| Frame {
+            buf,
+            data_range,
+            seq,
+            slot,
+        });
+        Ok(frame)
+    }
+
+    /// Returns the current page from the cache or the file.
+    fn read_frame(&self, id: u64) -> Result<Option<Frame>> {
+        let Some(cache) = &self.cache else {
+            return self.read_slots(id);
+        };
+        if let Some(frame) = cache.get(self.file, id) {
+            return Ok(Some(frame));
+        }
+
+        let Some(frame) = self.read_slots(id)? else {
+            return Ok(None);
+        };
+        // Only the data is kept in the cache.
+        let frame = Frame::new(frame.data(), frame.seq, frame.slot);
+        cache.insert(self.file, id, frame.clone());
+        Ok(Some(frame))
+    }
+
+    fn write_slot(&self, id: u64, page: Page, seq: u64, slot: u64) -> Result<()> {
+        let mut buf = self.encode(page, seq);
+        let data_len = buf.len() - PAGE_HEADER_LEN;
+        let mapped = self.io.map.is_some();
+        if mapped {
+            buf.resize(self.page_size, 0);
+        }
+        self.io
+            .write(&buf, self.offset(id) + slot * self.page_size as u64)?;
+        // Keep the file in whole pages so the last page can also be read from the mapping.
+        if mapped {
+            self.io.grow(self.offset(id + 1))?;
+        }
+
+        if let Some(cache) = &self.cache {
+            let frame = Frame::new(&buf[PAGE_HEADER_LEN..][..data_len], seq, slot);
+            cache.insert(self.file, id, frame);
+        }
         Ok(())
     }
 
-    // We need to ensure writing to main pages is atomic but for now, it is not possible.
-    // There is a risk of losing consistency if writing to main pages ended in torn write.
+    /// Write the page into the slot not holding the last durable version.
+    /// Even if the write is torn, that version is still readable.
     pub fn write_page_atomic(&self, id: u64, page: Page) -> Result<()> {
-        let buf = Self::into_data(page);
-        self.io.write(&buf, id * 4096)?;
+        self.preserve(id)?;
+        let frame = self.read_frame(id)?;
+        // Held during the write so a flush started after it covers the write.
+        let mut unflushed = self.unflushed.lock().unwrap();
+        let seq = frame.as_ref().map_or(1, |frame| frame.seq + 1);
+        let slot = match (unflushed.pages.get(&id), frame) {
+            // The slot written since the last flush isn't durable, so it is written again.
+            (Some(&(slot, _)), _) => slot,
+            (None, Some(frame)) => 1 - frame.slot,
+            (None, None) => 0,
+        };
+        self.write_slot(id, page, seq, slot)?;
+        let epoch = unflushed.epoch;
+        unflushed.pages.insert(id, (slot, epoch));
         Ok(())
     }
 
     pub fn read_page(&self, id: u64) -> Result<Option<Page>> {
-        let mut buf = vec![0u8; 4096];
-        self.io.read(&mut buf, id * 4096)?;
-
-        let stored_crc = u32::from_le_bytes(buf[0..4].try_into().unwrap());
-        let data_len = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
-        let data = &buf[8..(8 + data_len)];
-        let calc_crc = crc32fast::hash(data);
-        assert_eq!(stored_crc, calc_crc);
+        let Some(frame) = self.read_frame(id)? else {
+            return Ok(None);
+        };
 
-        match decode_page(data) {
+        match decode_page(frame.data()) {
             Ok(page) => Ok(Some(page)),
-            Err(_) => Ok(None),
+            Err(_) => Err(self.corruption(id, CorruptionKind::InvalidData)),
         }
     }
 
     pub fn read_page_ref(&self, id: u64) -> Result<Option<PageRef>> {
-        let mut buf = AlignedVec::with_capacity(4096);
-        buf.resize(4096, 0);
+        let Some(frame) = self.read_frame(id)? else {
+            return Ok(None);
+        };
+        self.frame_ref(id, frame).map(Some)
+    }
 
-        self.io.read(&mut buf, id * 4096)?;
+    fn frame_ref(&self, id: u64, frame: Frame) -> Result<PageRef> {
+        if self.checked && !check_page(frame.data()) {
+            return Err(self.corruption(id, CorruptionKind::InvalidData));
+        }
 
-        let stored_crc = u32::from_le_bytes(buf[0..4].try_into().unwrap());
-        let data_len = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
-        let data_range = 8..(8 + data_len);
-        let calc_crc = crc32fast::hash(&buf[data_range.clone()]);
-        assert_eq!(stored_crc, calc_crc);
+        let page_ref = PageRef {
+            buf: frame.buf,
+            data_range: frame.data_range,
+        };
 
-        let page_ref = PageRef { buf, data_range };
+        Ok(page_ref)
+    }
 
-        Ok(Some(page_ref))
+    pub fn add_snapshot(&self, preserved: &Arc<Preserved>) {
+        self.snapshots
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(preserved));
     }
 
+    /// Save the current version of the page into the snapshots which don't have it yet.
+    fn preserve(&self, id: u64) -> Result<()> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.retain(|s| s.strong_count() > 0);
+
+        for preserved in snapshots.iter().filter_map(Weak::upgrade) {
+            let mut pages = preserved.pages.write().unwrap();
+            if pages.contains_key(&id) {
+                continue;
+            }
+            // Copy the page not to keep the mapping which is overwritten later.
+            let frame = self
+                .read_frame(id)?
+                .map(|frame| Frame::new(frame.data(), frame.seq, frame.slot));
+            pages.insert(id, frame);
+        }
+        Ok(())
+    }
+
+    /// Read the page as it was when the snapshot was taken.
+    pub fn snapshot_page_ref(&self, id: u64, preserved: &Preserved) -> Result<PageRef> {
+        // Holding the lock, the page is not overwritten before it is saved.
+        let pages = preserved.pages.read().unwrap();
+        match pages.get(&id) {
+            Some(Some(frame)) => self.frame_ref(id, frame.clone()),
+            Some(None) => Err(self.corruption(id, CorruptionKind::Missing)),
+            None => {
+                let Some(frame) = self.read_frame(id)? else {
+                    return Err(self.no_page(id));
+                };
+                // The writer doesn't wait for the readers of a snapshot, so the page may be overwritten
+                // or truncated once the lock is released. A view of the mapping is copied.
+                let frame = match frame.buf {
+                    PageBuf::Mapped(_) => Frame::new(frame.data(), frame.seq, frame.slot),
+                    PageBuf::Heap(_) => frame,
+                };
+                self.frame_ref(id, frame)
+            }
+        }
+    }
+
+    /// Same as `read_page` but the page must exist.
+    pub fn expect_page(&self, id: u64) -> Result<Page> {
+        match self.read_page(id)? {
+            Some(page) => Ok(page),
+            None => Err(self.no_page(id)),
+        }
+    }
+
+    /// Same as `read_page_ref` but the page must exist.
+    pub fn expect_page_ref(&self, id: u64) -> Result<PageRef> {
+        match self.read_page_ref(id)? {
+            Some(page_ref) => Ok(page_ref),
+            None => Err(self.no_page(id)),
+        }
+    }
+
+    fn corruption(&self, id: u64, kind: CorruptionKind) -> Error {
+        Error::Corruption {
+            file: self.file,
+            page_id: id,
+            kind,
+        }
+    }
+
+    /// The number of pages without the trailing pages never written completely.
+    /// They are left by a crash while a split wrote a new main page.
+    /// A broken page before them is a corruption and is counted.
+    pub fn n_written_pages(&self) -> Result<u64> {
+        let mut n = self.n_pages()?;
+        while n > 0 && self.is_unwritten(n - 1)? {
+            n -= 1;
+        }
+        Ok(n)
+    }
+
+    /// True if no write of the page ever completed: no slot is valid and at most one write was torn.
+    fn is_unwritten(&self, id: u64) -> Result<bool> {
+        let mut buf = vec![0; self.page_size * N_SLOTS as usize];
+        self.io.read(&mut buf, self.offset(id))?;
+
+        let mut n_torn = 0;
+        for slot in buf.chunks(self.page_size) {
+            match parse_slot(slot) {
+                Slot::Empty => {}
+                Slot::Torn => n_torn += 1,
+                Slot::Valid { .. } => return Ok(false),
+            }
+        }
+        Ok(n_torn <= 1)
+    }
+
+    /// The error for the page not found. Tells if the page was never written or all the slots are broken.
+    fn no_page(&self, id: u64) -> Error {
+        let mut buf = vec![0; self.page_size * N_SLOTS as usize];
+        if let Err(e) = self.io.read(&mut buf, self.offset(id)) {
+            return e;
+        }
+
+        let torn = buf
+            .chunks(self.page_size)
+            .any(|slot| matches!(parse_slot(slot), Slot::Torn));
+        let kind = if torn {
+            CorruptionKind::Checksum
+        } else {
+            CorruptionKind::Missing
+        };
+        self.corruption(id, kind)
+    }
+
+    pub fn read_header(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        assert!(offset + buf.len() as u64 <= self.offset(0));
+        self.io.read(buf, offset)
+    }
+
+    pub fn write_header(&self, buf: &[u8], offset: u64) -> Result<()> {
+        assert!(offset + buf.len() as u64 <= self.offset(0));
+        self.io.write(buf, offset)
+    }
+
+    /// The barrier to order the writes. Skipped by `SyncPolicy::Never`.
     pub fn flush(&self) -> Result<()> {
+        if self.ordered {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Persist all the writes so far.
+    pub fn sync(&self) -> Result<()> {
+        let epoch = {
+            let mut unflushed = self.unflushed.lock().unwrap();
+            unflushed.epoch += 1;
+            unflushed.epoch - 1
+        };
         self.io.flush()?;
+        // The writes made while flushing may not be durable yet.
+        let mut unflushed = self.unflushed.lock().unwrap();
+        unflushed.pages.retain(|_, (_, e)| *e > epoch);
+        Ok(())
+    }
+
+    /// True if no slot of any page was ever written. Only the header may be.
+    pub fn is_blank(&self) -> Result<bool> {
+        let mut buf = vec![0; self.page_size * N_SLOTS as usize];
+        for id in 0..self.n_pages()? {
+            self.io.read(&mut buf, self.offset(id))?;
+            if buf
+                .chunks(self.page_size)
+                .any(|slot| !matches!(parse_slot(slot), Slot::Empty))
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The number of pages in the file including the partially written last page.
+    pub fn n_pages(&self) -> Result<u64> {
+        let len = self.io.store.len()?;
+        let body = len.saturating_sub(self.offset(0));
+        Ok(body.div_ceil(self.page_size as u64 * N_SLOTS))
+    }
+
+    /// Drop the pages from `id` and persist the new length.
+    /// With mmap, the caller must make sure no other thread reads the dropped pages:
+    /// `SharedForeverHash` holds the lock of the bucket, and snapshots copy the pages they read.
+    pub fn truncate(&self, id: u64) -> Result<()> {
+        for i in id..self.n_pages()? {
+            self.preserve(i)?;
+        }
+        if let Some(cache) = &self.cache {
+            cache.remove_from(self.file, id);
+        }
+        self.unflushed.lock().unwrap().pages.retain(|&i, _| i < id);
+        self.io.truncate(self.offset(id))?;
+        self.flush()?;
         Ok(())
     }
 }
@@ -108,20 +590,148 @@ mod tests {
 
     #[test]
     fn test_read_page_ref() {
-        let f = tempfile::NamedTempFile::new().unwrap();
-        let device = Device::new(f.reopen().unwrap());
+        let device = Device::new_overflow(Box::new(MemIo::new()), 4096);
 
-        let mut page = Page {
-            kv_pairs: HashMap::new(),
-            overflow_id: None,
-        };
+        let mut page = Page::new();
         page.insert(vec![1; 32], vec![1; 16]);
         page.insert(vec![2; 32], vec![2; 16]);
 
-        device.write_page(3, page).unwrap();
+        device.write_page_atomic(3, page).unwrap();
 
         let page_ref = device.read_page_ref(3).unwrap().unwrap();
-        assert_eq!(page_ref.get_value(&vec![1; 32]), Some(&vec![1; 16][..]));
-        assert_eq!(page_ref.get_value(&vec![2; 32]), Some(&vec![2; 16][..]));
+        assert_eq!(page_ref.get_value(&[1; 32]), Some(&vec![1; 16][..]));
+        assert_eq!(page_ref.get_value(&[2; 32]), Some(&vec![2; 16][..]));
+    }
+
+    #[test]
+    fn test_torn_write_atomic() {
+        let device = Device::new_main(Box::new(MemIo::new()), 4096);
+
+        let mut page = Page::new();
+        page.insert(vec![1; 32], vec![1; 16]);
+        device.write_page_atomic(0, page).unwrap();
+        device.flush().unwrap();
+
+        let mut page = Page::new();
+        page.insert(vec![2; 32], vec![2; 16]);
+        device.write_page_atomic(0, page).unwrap();
+
+        let page = device.read_page(0).unwrap().unwrap();
+        assert!(page.contains(&[2; 32]));
+
+        // Tear the latest write. The page goes back to the previous one.
+        let offset = device.offset(0) + 4096 + 20;
+        device.io.write(&[0xff; 8], offset).unwrap();
+
+        let page = device.read_page(0).unwrap().unwrap();
+        assert!(page.contains(&[1; 32]));
+        assert!(!page.contains(&[2; 32]));
+
+        // The next write goes to the torn slot.
+        let mut page = Page::new();
+        page.insert(vec![3; 32], vec![3; 16]);
+        device.write_page_atomic(0, page).unwrap();
+
+        let page_ref = device.read_page_ref(0).unwrap().unwrap();
+        assert_eq!(page_ref.get_value(&[3; 32]), Some(&vec![3; 16][..]));
+    }
+
+    #[test]
+    fn test_rewrite_before_flush() {
+        let device = Device::new_main(Box::new(MemIo::new()), 4096);
+        let page = |i: u8| {
+            let mut page = Page::new();
+            page.insert(vec![i; 32], vec![i; 16]);
+            page
+        };
+
+        device.write_page_atomic(0, page(1)).unwrap();
+        device.flush().unwrap();
+
+        // The rewrites until the next flush go to the same slot, not over the flushed version.
+        device.write_page_atomic(0, page(2)).unwrap();
+        device.write_page_atomic(0, page(3)).unwrap();
+        assert!(device.read_page(0).unwrap().unwrap().contains(&[3; 32]));
+        let offset = device.offset(0) + 4096 + 20;
+        device.io.write(&[0xff; 8], offset).unwrap();
+        assert!(device.read_page(0).unwrap().unwrap().contains(&[1; 32]));
+
+        // After the flush, the next write goes to the other slot.
+        device.write_page_atomic(0, page(4)).unwrap();
+        device.flush().unwrap();
+        device.write_page_atomic(0, page(5)).unwrap();
+        let offset = device.offset(0) + 20;
+        device.io.write(&[0xff; 8], offset).unwrap();
+        assert!(device.read_page(0).unwrap().unwrap().contains(&[4; 32]));
+    }
+
+    #[test]
+    fn test_invalid_data() {
+        let device = Device::new_overflow(Box::new(MemIo::new()), 4096);
+
+        // The checksum is valid but the data isn't a page.
+        let data = [0xff; 64];
+        let seq = 1u64;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&seq.to_le_bytes());
+        hasher.update(&data);
+        let mut buf = hasher.finalize().to_le_bytes().to_vec();
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&seq.to_le_bytes());
+        buf.extend_from_slice(&data);
+        device.io.write(&buf, device.offset(0)).unwrap();
+
+        let invalid_data = |r: Result<_>| {
+            matches!(
+                r,
+                Err(Error::Corruption {
+                    file: PageFile::Overflow,
+                    page_id: 0,
+                    kind: CorruptionKind::InvalidData
+                })
+            )
+        };
+        assert!(invalid_data(device.read_page(0).map(|_| ())));
+        assert!(device.read_page_ref(0).is_ok());
+
+        let device = device.with_checked(true);
+        assert!(invalid_data(device.read_page_ref(0).map(|_| ())));
+        assert!(matches!(
+            device.expect_page_ref(1),
+            Err(Error::Corruption {
+                kind: CorruptionKind::Missing,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_mmap_grow() {
+        let f = tempfile::NamedTempFile::new().unwrap();
+        let store = FileIo::new(f.reopen().unwrap());
+        let device = Device::new_overflow(Box::new(store), 4096)
+            .with_mmap()
+            .unwrap();
+
+        // Not in the file yet.
+        assert!(device.read_page_ref(0).unwrap().is_none());
+
+        // Write beyond the initial mapping.
+        for id in [0u64, 1000] {
+            let mut page = Page::new();
+            page.insert(id.to_le_bytes().to_vec(), vec![1; 16]);
+            device.write_page_atomic(id, page).unwrap();
+        }
+        device.flush().unwrap();
+
+        for id in [0u64, 1000] {
+            let page_ref = device.read_page_ref(id).unwrap().unwrap();
+            assert!(matches!(page_ref.buf, PageBuf::Mapped(_)));
+            assert_eq!(page_ref.get_value(&id.to_le_bytes()), Some(&[1; 16][..]));
+        }
+
+        device.truncate(1).unwrap();
+        assert!(device.read_page_ref(0).unwrap().is_some());
+        assert!(device.read_page_ref(1000).unwrap().is_none());
     }
 }
@@ -1,9 +1,56 @@
+/// The page files of a table.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PageFile {
+    Main,
+    Overflow,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CorruptionKind {
+    /// No page is written where a page is expected.
+    Missing,
+    /// The checksum doesn't match in any slot.
+    Checksum,
+    /// The checksum matches but the data isn't a valid page.
+    InvalidData,
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    #[error("Key-value pair is too large to fit in a page")]
+    TooLarge,
+    #[error("The key can't be hashed by the hasher")]
+    InvalidKey,
+    #[error("Unsupported format version {0}")]
+    UnsupportedVersion(u32),
+    #[error("No valid superblock in the main page file")]
+    NoSuperblock,
+    #[error("Invalid page size {0}")]
+    InvalidPageSize(u32),
+    #[error("Invalid filter length {0}")]
+    InvalidFilterLen(u32),
+    #[error("Hasher params of {0} bytes are too long")]
+    HasherParamsTooLong(usize),
+    #[error("Page size mismatch: the table uses {0} bytes pages")]
+    PageSizeMismatch(u32),
+    #[error("Hasher mismatch: the table uses hasher {0}")]
+    HashMismatch(u8),
+    #[error("Corrupted page {page_id} in the {file:?} page file: {kind:?}")]
+    Corruption {
+        file: PageFile,
+        page_id: u64,
+        kind: CorruptionKind,
+    },
     #[error(transparent)]
     Rkyv(#[from] rkyv::rancor::Error),
     #[error(transparent)]
     IO(#[from] std::io::Error),
 }
 
+impl Error {
+    pub fn is_corruption(&self) -> bool {
+        matches!(self, Error::Corruption { .. })
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,56 @@
+use xxhash_rust::xxh3::xxh3_128;
+
+/// The number of bits set per key.
+const N_HASHES: u64 = 3;
+
+// The Bloom filter of a chain is kept in its main page.
+// The bits are derived from a hash independent of the hasher choosing the bucket,
+// since all the keys of a bucket share the low bits of that hash.
+fn bit_positions(key: &[u8], n_bits: u64) -> impl Iterator<Item = u64> {
+    let h = xxh3_128(key);
+    let h1 = h as u64;
+    let h2 = (h >> 64) as u64 | 1;
+    (0..N_HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % n_bits)
+}
+
+pub fn filter_add(bits: &mut [u8], key: &[u8]) {
+    for i in bit_positions(key, bits.len() as u64 * 8) {
+        bits[(i / 8) as usize] |= 1 << (i % 8);
+    }
+}
+
+/// False if the key is surely not in the chain.
+pub fn filter_contains(bits: &[u8], key: &[u8]) -> bool {
+    if bits.is_empty() {
+        return true;
+    }
+    bit_positions(key, bits.len() as u64 * 8).all(|i| bits[(i / 8) as usize] & (1 << (i % 8)) != 0)
+}
+
+pub fn build_filter<'a>(len: usize, keys: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut bits = vec![0; len];
+    for k in keys {
+        filter_add(&mut bits, k);
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negative() {
+        let keys: Vec<Vec<u8>> = (0..100u64).map(|i| i.to_le_bytes().to_vec()).collect();
+        let bits = build_filter(128, keys.iter().map(|k| k.as_slice()));
+        assert!(keys.iter().all(|k| filter_contains(&bits, k)));
+
+        // About 1% false positives with 10 bits per key.
+        let n_false = (100..10100u64)
+            .filter(|i| filter_contains(&bits, &i.to_le_bytes()))
+            .count();
+        assert!(n_false < 500, "{n_false}");
+
+        assert!(filter_contains(&[], b"any"));
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt::Debug;
+use std::hash::Hasher;
+
+/// Hash function to choose the main page of a key.
+///
+/// The hasher is recorded in the superblock by `id` and `params`
+/// and opening the table with another hasher fails.
+/// Ids below 128 are reserved for the built-in hashers.
+pub trait KeyHasher: Debug + Send + Sync {
+    fn id(&self) -> u8;
+
+    /// Parameters to be recorded with the id, such as the seed. At most 256 bytes.
+    fn params(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    /// Returns `None` if the hasher can't handle the key.
+    fn hash(&self, key: &[u8]) -> Option<u64>;
+}
+
+/// Use the first 8 bytes of the key as the hash to eliminate the cost of hashing.
+/// The keys are expected to be random enough like digests.
+/// Keys shorter than 8 bytes are padded with zeros.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityU64;
+
+impl KeyHasher for IdentityU64 {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn hash(&self, key: &[u8]) -> Option<u64> {
+        let n = key.len().min(8);
+        let mut a = [0; 8];
+        a[..n].copy_from_slice(&key[..n]);
+        Some(u64::from_le_bytes(a))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Xxh3;
+
+impl KeyHasher for Xxh3 {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn hash(&self, key: &[u8]) -> Option<u64> {
+        Some(xxhash_rust::xxh3::xxh3_64(key))
+    }
+}
+
+/// SipHash-1-3 with a secret seed. Use this if the keys can be chosen by an attacker.
+#[derive(Debug, Clone, Copy)]
+pub struct SipHash13 {
+    pub k0: u64,
+    pub k1: u64,
+}
+
+impl KeyHasher for SipHash13 {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn params(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16);
+        out.extend_from_slice(&self.k0.to_le_bytes());
+        out.extend_from_slice(&self.k1.to_le_bytes());
+        out
+    }
+
+    fn hash(&self, key: &[u8]) -> Option<u64> {
+        let mut hasher = siphasher::sip::SipHasher13::new_with_keys(self.k0, self.k1);
+        hasher.write(key);
+        Some(hasher.finish())
+    }
+}
+
+#[cfg(not(feature = "hash"))]
+pub fn default_hasher() -> std::sync::Arc<dyn KeyHasher> {
+    std::sync::Arc::new(IdentityU64)
+}
+
+#[cfg(feature = "hash")]
+pub fn default_hasher() -> std::sync::Arc<dyn KeyHasher> {
+    std::sync::Arc::new(Xxh3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_siphash_seed() {
+        let key = b"hello, world";
+        let a = SipHash13 { k0: 1, k1: 2 };
+        let b = SipHash13 { k0: 1, k1: 3 };
+        assert_eq!(a.hash(key), a.hash(key));
+        assert!(a.hash(key).is_some());
+        assert_ne!(a.hash(key), b.hash(key));
+        assert_ne!(a.params(), b.params());
+    }
+
+    #[test]
+    fn test_identity_short_key() {
+        assert_eq!(IdentityU64.hash(&[]), Some(0));
+        assert_eq!(IdentityU64.hash(&[1, 2]), Some(0x0201));
+        assert_eq!(
+            IdentityU64.hash(&[1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            Some(0x0807060504030201)
+        );
+    }
+}
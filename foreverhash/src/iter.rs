@@ -0,0 +1,165 @@
+use super::*;
+
+/// The position to resume an iteration from.
+///
+/// The cursor points to a main page and the number of pairs already returned from its chain.
+/// If the table is modified between the iterations, pairs may be returned twice or missed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Cursor {
+    main_page_id: u64,
+    n_done: u64,
+}
+
+impl Cursor {
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut out = [0; 16];
+        out[0..8].copy_from_slice(&self.main_page_id.to_le_bytes());
+        out[8..16].copy_from_slice(&self.n_done.to_le_bytes());
+        out
+    }
+
+    pub fn from_bytes(buf: &[u8; 16]) -> Self {
+        Self {
+            main_page_id: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            n_done: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+/// The pages to iterate over. The table or a snapshot of it.
+pub(crate) trait Pages {
+    fn page_ref(&self, page_id: PageId) -> Result<PageRef>;
+    fn n_main_pages(&self) -> u64;
+}
+
+impl Pages for ForeverHash {
+    fn page_ref(&self, page_id: PageId) -> Result<PageRef> {
+        match page_id {
+            PageId::Main(b) => self.main_pages.expect_page_ref(b),
+            PageId::Overflow(id) => self.overflow_pages.expect_page_ref(id),
+        }
+    }
+
+    fn n_main_pages(&self) -> u64 {
+        ForeverHash::n_main_pages(self)
+    }
+}
+
+/// Iterates over all the pairs chain by chain. Only one page is loaded at a time.
+pub struct Iter<'a> {
+    db: &'a dyn Pages,
+    main_page_id: u64,
+    /// The number of pairs returned from the current chain.
+    n_done: u64,
+    /// The number of pairs to skip in the current chain on resume.
+    n_skip: u64,
+    next_page: Option<PageId>,
+    buf: VecDeque<(Vec<u8>, Vec<u8>)>,
+    failed: bool,
+}
+
+impl<'a> Iter<'a> {
+    pub(crate) fn new(db: &'a dyn Pages, cursor: Cursor) -> Self {
+        Self {
+            db,
+            main_page_id: cursor.main_page_id,
+            n_done: cursor.n_done,
+            n_skip: cursor.n_done,
+            next_page: Some(PageId::Main(cursor.main_page_id)),
+            buf: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    /// Returns the cursor to resume the iteration after the pairs already returned.
+    pub fn cursor(&self) -> Cursor {
+        Cursor {
+            main_page_id: self.main_page_id,
+            n_done: self.n_done,
+        }
+    }
+
+    fn load_page(&mut self, page_id: PageId) -> Result<()> {
+        let page = self.db.page_ref(page_id)?;
+
+        for (k, v) in page.kv_pairs() {
+            if self.n_skip > 0 {
+                self.n_skip -= 1;
+                continue;
+            }
+            self.buf.push_back((k.to_owned(), v.to_owned()));
+        }
+        self.next_page = page.overflow_id().map(PageId::Overflow);
+
+        Ok(())
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        loop {
+            if let Some(pair) = self.buf.pop_front() {
+                self.n_done += 1;
+                return Some(Ok(pair));
+            }
+
+            match self.next_page {
+                Some(page_id) => {
+                    if self.main_page_id >= self.db.n_main_pages() {
+                        return None;
+                    }
+                    if let Err(e) = self.load_page(page_id) {
+                        self.failed = true;
+                        return Some(Err(e));
+                    }
+                }
+                None => {
+                    self.main_page_id += 1;
+                    self.n_done = 0;
+                    self.n_skip = 0;
+                    self.next_page = Some(PageId::Main(self.main_page_id));
+                }
+            }
+        }
+    }
+}
+
+pub struct Keys<'a>(pub(crate) Iter<'a>);
+
+impl Iterator for Keys<'_> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|r| r.map(|(k, _)| k))
+    }
+}
+
+pub struct Values<'a>(pub(crate) Iter<'a>);
+
+impl Iterator for Values<'_> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|r| r.map(|(_, v)| v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_bytes() {
+        let cursor = Cursor {
+            main_page_id: 42,
+            n_done: 7,
+        };
+        assert_eq!(Cursor::from_bytes(&cursor.to_bytes()), cursor);
+    }
+}
@@ -1,25 +1,64 @@
 use rkyv::util::AlignedVec;
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::ops::Range;
 use std::os::unix::fs::FileExt;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 
 mod error;
-pub use error::Error;
 use error::Result;
+pub use error::{CorruptionKind, Error, PageFile};
 
 mod device;
 use device::Device;
 mod op;
+use op::{Applied, Change};
+pub use op::{Problem, RepairReport, TableStats, VerifyReport, WriteBatch};
 
 mod page;
 use page::*;
 
+mod hasher;
+pub use hasher::{IdentityU64, KeyHasher, SipHash13, Xxh3};
+
+mod iter;
+use iter::Pages;
+pub use iter::{Cursor, Iter, Keys, Values};
+
+mod store;
+pub use store::{BlockIo, FaultyIo, FileIo, MemIo};
+
+mod mmap;
+use mmap::Mapping;
+
+mod cache;
+pub use cache::CacheStats;
+use cache::{Frame, PageCache};
+
+mod superblock;
+use superblock::{FORMAT_VERSION, MAX_HASHER_PARAMS_LEN, Superblock};
+
+mod filter;
+use filter::{build_filter, filter_add, filter_contains};
+
+mod sync;
+pub use sync::SyncPolicy;
+
+mod snapshot;
+use snapshot::Preserved;
+pub use snapshot::Snapshot;
+
+mod shared;
+pub use shared::{SharedForeverHash, SharedGuard};
+
 #[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Debug)]
 struct Page {
     kv_pairs: HashMap<Vec<u8>, Vec<u8>>,
     overflow_id: Option<u64>,
+    /// The Bloom filter of the keys in the chain. Only in main pages.
+    filter: Option<Vec<u8>>,
 }
 
 impl Page {
@@ -27,6 +66,7 @@ impl Page {
         Self {
             kv_pairs: HashMap::new(),
             overflow_id: None,
+            filter: None,
         }
     }
 
@@ -35,141 +75,815 @@ impl Page {
         self.kv_pairs.insert(key, value)
     }
 
-    fn contains(&self, key: &[u8]) -> bool {
-        self.kv_pairs.contains_key(key)
-    }
-}
-
-fn calc_max_kv_per_page(ksize: usize, vsize: usize) -> u8 {
-    for i in 0..=255 {
-        let mut page = Page {
-            kv_pairs: HashMap::new(),
-            overflow_id: Some(1),
-        };
-        for j in 0..i {
-            page.insert(vec![j; ksize], vec![j; vsize]);
+    /// Insert the pair only if the encoded page still fits in `max_len`.
+    /// Otherwise, the page is left unchanged and the pair is given back.
+    fn try_insert(&mut self, key: Vec<u8>, value: Vec<u8>, max_len: usize) -> TryInsert {
+        let old = self.kv_pairs.insert(key.clone(), value);
+        if page_fits(self, max_len) {
+            return Ok(old);
         }
 
-        let buf = encode_page(&page);
-        if buf.len() > 4088 {
-            assert!(i > 2);
-            return i - 1;
+        let value = self.kv_pairs.remove(&key).unwrap();
+        if let Some(old) = old {
+            self.kv_pairs.insert(key.clone(), old);
         }
+        Err((key, value))
     }
 
-    255
+    fn contains(&self, key: &[u8]) -> bool {
+        self.kv_pairs.contains_key(key)
+    }
+
+    /// False if the key is surely not in the chain of this main page.
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|bits| filter_contains(bits, key))
+    }
 }
 
+type TryInsert = std::result::Result<Option<Vec<u8>>, (Vec<u8>, Vec<u8>)>;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 enum PageId {
     Main(u64),
     Overflow(u64),
 }
 
 pub struct ForeverHash {
-    main_pages: Device,
+    // Shared with the readers of `SharedForeverHash`.
+    main_pages: Arc<Device>,
     main_base_level: u8,
     next_split_main_page_id: u64,
 
-    overflow_pages: Device,
+    overflow_pages: Arc<Device>,
     next_overflow_id: u64,
+    /// Overflow pages no longer referenced from any chain.
+    free_overflow_ids: Vec<u64>,
+    /// Freed overflow pages which may still be referenced from the pages on the disk.
+    /// These can be reused after the pages are persisted.
+    pending_free_overflow_ids: Vec<u64>,
 
     n_items: u64,
-    max_kv_per_page: Option<u8>,
+    /// The estimated bytes all the kv-pairs take in the pages.
+    n_bytes: u64,
+
+    page_size: u32,
+    hasher: Arc<dyn KeyHasher>,
+    superblock_seq: u64,
+    closed: bool,
+    sync_policy: SyncPolicy,
+    last_sync: Instant,
+    filter_len: usize,
+
+    /// The journals of the batches in the superblock, oldest first.
+    /// Dropped lazily on the next checkpoint or batch after the pages of the batch are persisted.
+    journals: Vec<op::Journal>,
+
+    wal: Option<op::Wal>,
+
+    cache: Option<Arc<PageCache>>,
+}
+
+/// Options to open a table.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The size of the pages in bytes. It must be a power of two between 1 KiB and 1 MiB.
+    /// This is fixed when the table is created. Opening the table with a different size fails.
+    pub page_size: u32,
+    /// The hash function to choose the main page of a key.
+    /// This is fixed when the table is created. Opening the table with another hasher fails.
+    /// The default is `IdentityU64`, or `Xxh3` if the `hash` feature is enabled.
+    pub hasher: Arc<dyn KeyHasher>,
+    /// The capacity of the page cache in bytes. The cache is shared by the main and overflow pages
+    /// and main pages are kept longer. 0 disables the cache.
+    pub cache_size: usize,
+    /// If true, pages are read from the mappings of the files instead of copying them into buffers.
+    /// The page cache is not used in this mode.
+    pub mmap: bool,
+    /// If true, pages are validated before they are accessed without copying.
+    /// Otherwise only the checksum protects against corrupted data.
+    pub checked_reads: bool,
+    /// When the writes are flushed to the disk.
+    pub sync_policy: SyncPolicy,
+    /// If true, the mutations are logged in `<main page file>.wal` before the pages change
+    /// and applied again on open after a crash.
+    pub wal: bool,
+    /// The bytes of the Bloom filter kept in each main page for the keys of its chain.
+    /// A lookup of a missing key then usually stops at the main page. At most a quarter of the page.
+    /// 0 disables the filters. Chains built before they were enabled have no filter until they are split.
+    pub filter_len: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            page_size: 4096,
+            hasher: hasher::default_hasher(),
+            cache_size: 0,
+            mmap: false,
+            checked_reads: false,
+            sync_policy: SyncPolicy::default(),
+            wal: false,
+            filter_len: 0,
+        }
+    }
 }
 
 impl ForeverHash {
     pub fn new(main_page_file: &Path, overflow_page_file: &Path) -> Result<Self> {
-        let main_page_file = File::options()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(main_page_file)?;
-
-        let main_pages = Device::new(main_page_file);
+        let main = FileIo::open(main_page_file)?;
+        let overflow = FileIo::open(overflow_page_file)?;
+        Self::new_with(Box::new(main), Box::new(overflow), &Options::default())
+    }
 
-        let overflow_page_file = File::options()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(overflow_page_file)?;
+    fn new_with(
+        main: Box<dyn BlockIo>,
+        overflow: Box<dyn BlockIo>,
+        options: &Options,
+    ) -> Result<Self> {
+        let page_size = options.page_size;
+        if !page_size.is_power_of_two() || !(1024..=1 << 20).contains(&page_size) {
+            return Err(Error::InvalidPageSize(page_size));
+        }
+        if options.filter_len > page_size / 4 {
+            return Err(Error::InvalidFilterLen(options.filter_len));
+        }
+        let params_len = options.hasher.params().len();
+        if params_len > MAX_HASHER_PARAMS_LEN {
+            return Err(Error::HasherParamsTooLong(params_len));
+        }
 
-        let overflow_pages = Device::new(overflow_page_file);
+        let cache = (options.cache_size > 0 && !options.mmap)
+            .then(|| Arc::new(PageCache::new(options.cache_size)));
+
+        let ordered = options.sync_policy.ordered();
+        let mut main_pages = Device::new_main(main, page_size as usize)
+            .with_cache(cache.clone())
+            .with_checked(options.checked_reads)
+            .with_ordered(ordered);
+        let mut overflow_pages = Device::new_overflow(overflow, page_size as usize)
+            .with_cache(cache.clone())
+            .with_checked(options.checked_reads)
+            .with_ordered(ordered);
+
+        if options.mmap {
+            main_pages = main_pages.with_mmap()?;
+            overflow_pages = overflow_pages.with_mmap()?;
+        }
 
         Ok(Self {
-            main_pages,
+            main_pages: Arc::new(main_pages),
             main_base_level: 1,
             next_split_main_page_id: 0,
 
-            overflow_pages,
+            overflow_pages: Arc::new(overflow_pages),
             next_overflow_id: 0,
+            free_overflow_ids: Vec::new(),
+            pending_free_overflow_ids: Vec::new(),
 
-            max_kv_per_page: None,
             n_items: 0,
+            n_bytes: 0,
+
+            page_size,
+            hasher: options.hasher.clone(),
+            superblock_seq: 0,
+            // Not to write the superblock on drop until the table is opened.
+            closed: true,
+            sync_policy: options.sync_policy,
+            last_sync: Instant::now(),
+            filter_len: options.filter_len as usize,
+
+            journals: Vec::new(),
+
+            wal: None,
+
+            cache,
         })
     }
 
     pub fn open(main_page_file: &Path, overflow_page_file: &Path) -> Result<Self> {
-        let mut db = Self::new(main_page_file, overflow_page_file)?;
+        Self::open_with(main_page_file, overflow_page_file, Options::default())
+    }
 
-        let n_main_pages = op::Restore { db: &mut db }.exec()?;
+    pub fn open_with(
+        main_page_file: &Path,
+        overflow_page_file: &Path,
+        options: Options,
+    ) -> Result<Self> {
+        let main = FileIo::open(main_page_file)?;
+        let overflow = FileIo::open(overflow_page_file)?;
+        let wal = if options.wal {
+            let mut path = main_page_file.as_os_str().to_owned();
+            path.push(".wal");
+            Some(Box::new(FileIo::open(Path::new(&path))?) as Box<dyn BlockIo>)
+        } else {
+            None
+        };
+        Self::open_stores(Box::new(main), Box::new(overflow), wal, options)
+    }
+
+    /// Open the table on the given stores instead of files.
+    /// `Options::mmap` requires the stores to be on files. `Options::wal` requires `open_with_wal_stores`.
+    pub fn open_with_stores(
+        main: Box<dyn BlockIo>,
+        overflow: Box<dyn BlockIo>,
+        options: Options,
+    ) -> Result<Self> {
+        if options.wal {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput).into());
+        }
+        Self::open_stores(main, overflow, None, options)
+    }
 
-        // Invariant: there are at least two valid main pages.
-        if n_main_pages < 2 {
-            op::Init { db: &mut db }.exec()?;
-            op::Restore { db: &mut db }.exec()?;
+    /// Open the table with the redo log on the given store.
+    pub fn open_with_wal_stores(
+        main: Box<dyn BlockIo>,
+        overflow: Box<dyn BlockIo>,
+        wal: Box<dyn BlockIo>,
+        options: Options,
+    ) -> Result<Self> {
+        Self::open_stores(main, overflow, Some(wal), options)
+    }
+
+    fn open_stores(
+        main: Box<dyn BlockIo>,
+        overflow: Box<dyn BlockIo>,
+        wal: Option<Box<dyn BlockIo>>,
+        options: Options,
+    ) -> Result<Self> {
+        let mut db = Self::new_with(main, overflow, &options)?;
+        let (wal, records) = match wal {
+            Some(store) => {
+                let (wal, records) = op::Wal::open(store)?;
+                (Some(wal), records)
+            }
+            None => (None, Vec::new()),
+        };
+
+        let sb = Superblock::read(&db.main_pages)?;
+        if let Some(sb) = &sb {
+            db.check_superblock(sb)?;
+            db.superblock_seq = sb.seq;
+        } else if db.main_pages.is_blank()? && db.overflow_pages.is_blank()? {
+            // A new table, or a crash while its first superblock was written.
+            // The superblock is written before the first pages, so pages with no superblock
+            // are not a table of this format.
+            db.commit_superblock(false)?;
+        } else {
+            return Err(Error::NoSuperblock);
         }
 
+        match sb {
+            // If the table was closed cleanly, the superblock is up to date.
+            Some(sb) if sb.clean => {
+                db.main_base_level = sb.main_base_level;
+                db.next_split_main_page_id = sb.next_split_main_page_id;
+                db.next_overflow_id = sb.next_overflow_id;
+                db.n_items = sb.n_items;
+                db.n_bytes = sb.n_bytes;
+                db.free_overflow_ids = db.read_free_overflow_ids(sb.free_overflow_head)?;
+            }
+            // Otherwise, traverse all the pages.
+            sb => {
+                // Read the journals before `Restore` counts their pages as free.
+                // A journal which didn't reach the disk belongs to a batch not committed.
+                let mut journals = Vec::new();
+                for head in sb.map(|sb| sb.journals).unwrap_or_default() {
+                    if let Some((batch, ids)) = op::read_journal(&db, &head)? {
+                        journals.push((batch, ids, head));
+                    }
+                }
+
+                let n_main_pages = op::Restore { db: &mut db }.exec()?;
+
+                // Invariant: there are at least two valid main pages.
+                // Fewer only if the table was created but the first pages weren't written.
+                if n_main_pages < 2 {
+                    op::Init { db: &mut db }.exec()?;
+                    op::Restore { db: &mut db }.exec()?;
+                }
+
+                // The batches may be partly applied. Apply them again.
+                for (batch, ids, head) in journals {
+                    db.free_overflow_ids.retain(|id| !ids.contains(id));
+                    db.journals.push(op::Journal {
+                        head,
+                        ids,
+                        keys: batch.keys().cloned().collect(),
+                        persisted: false,
+                    });
+                    op::Batch { db: &mut db }.replay(batch)?;
+                }
+            }
+        }
+
+        // The records are not logged again while they are applied.
+        op::Redo { db: &mut db }.exec(records)?;
+        db.wal = wal;
+        db.checkpoint()?;
+
+        // Mark the table dirty until it is closed.
+        db.commit_superblock(false)?;
+        db.closed = false;
+
         Ok(db)
     }
 
-    // The key must be at least 64 bits.
-    #[cfg(not(feature = "hash"))]
-    fn hash_key(&self, key: &[u8]) -> u64 {
-        let a: [u8; 8] = key[0..8].try_into().ok().unwrap();
-        u64::from_le_bytes(a)
+    /// Check all the pages of the table without modifying the files.
+    /// The table must not be open. Unlike `open`, nothing is repaired and the problems found are reported.
+    pub fn verify(
+        main_page_file: &Path,
+        overflow_page_file: &Path,
+        options: Options,
+    ) -> Result<VerifyReport> {
+        let main = FileIo::new(File::open(main_page_file)?);
+        let overflow = FileIo::new(File::open(overflow_page_file)?);
+        Self::verify_stores(Box::new(main), Box::new(overflow), options)
     }
 
-    #[cfg(feature = "hash")]
-    fn hash_key(&self, key: &[u8]) -> u64 {
-        xxhash_rust::xxh3::xxh3_64(key)
+    pub fn verify_stores(
+        main: Box<dyn BlockIo>,
+        overflow: Box<dyn BlockIo>,
+        options: Options,
+    ) -> Result<VerifyReport> {
+        // The table is never opened so nothing is written on drop.
+        let mut db = Self::new_with(main, overflow, &options)?;
+        op::Verify { db: &mut db }.exec()
     }
 
-    fn calc_main_page_id(&self, key: &[u8]) -> u64 {
-        let hash = self.hash_key(key);
+    /// Copy all the pairs still readable from a damaged table into a new table.
+    /// The pages are read even if they are not reachable and the pages which can't be read are reported.
+    /// The destination files must be empty.
+    pub fn repair(
+        src_main_page_file: &Path,
+        src_overflow_page_file: &Path,
+        dst_main_page_file: &Path,
+        dst_overflow_page_file: &Path,
+        options: Options,
+    ) -> Result<RepairReport> {
+        let src_main = FileIo::new(File::open(src_main_page_file)?);
+        let src_overflow = FileIo::new(File::open(src_overflow_page_file)?);
+        let dst_main = FileIo::open(dst_main_page_file)?;
+        let dst_overflow = FileIo::open(dst_overflow_page_file)?;
+        Self::repair_stores(
+            Box::new(src_main),
+            Box::new(src_overflow),
+            Box::new(dst_main),
+            Box::new(dst_overflow),
+            options,
+        )
+    }
 
-        let b = hash & ((1 << self.main_base_level) - 1);
-        if b < self.next_split_main_page_id {
-            hash & ((1 << (self.main_base_level + 1)) - 1)
-        } else {
-            b
+    pub fn repair_stores(
+        src_main: Box<dyn BlockIo>,
+        src_overflow: Box<dyn BlockIo>,
+        dst_main: Box<dyn BlockIo>,
+        dst_overflow: Box<dyn BlockIo>,
+        options: Options,
+    ) -> Result<RepairReport> {
+        if !dst_main.is_empty()? || !dst_overflow.is_empty()? {
+            return Err(std::io::Error::from(std::io::ErrorKind::AlreadyExists).into());
+        }
+
+        // The source is never opened so nothing is written to it.
+        let mut src = Self::new_with(src_main, src_overflow, &options)?;
+        let mut journal: Option<(WriteBatch, Vec<u64>)> = None;
+        if let Some(sb) = Superblock::read(&src.main_pages)? {
+            src.check_superblock(&sb)?;
+            // The journals of a dirty table, merged in order. If one can't be read, the batch is lost.
+            let heads = if sb.clean { &[][..] } else { &sb.journals[..] };
+            for head in heads {
+                if let Ok(Some((batch, ids))) = op::read_journal(&src, head) {
+                    let (merged, merged_ids) = journal.get_or_insert_default();
+                    merged.extend(batch);
+                    merged_ids.extend(ids);
+                }
+            }
+        }
+
+        let mut dst = Self::open_with_stores(dst_main, dst_overflow, options)?;
+        let report = op::Repair {
+            src: &mut src,
+            dst: &mut dst,
+            journal,
+        }
+        .exec()?;
+        dst.close()?;
+
+        Ok(report)
+    }
+
+    fn check_superblock(&self, sb: &Superblock) -> Result<()> {
+        if sb.version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(sb.version));
+        }
+        if sb.page_size != self.page_size {
+            return Err(Error::PageSizeMismatch(sb.page_size));
+        }
+        if sb.hasher_id != self.hasher.id() || sb.hasher_params != self.hasher.params() {
+            return Err(Error::HashMismatch(sb.hasher_id));
+        }
+        Ok(())
+    }
+
+    /// Free overflow pages are chained through `overflow_id` when the table is closed.
+    fn read_free_overflow_ids(&self, head: Option<u64>) -> Result<Vec<u64>> {
+        let mut out = Vec::new();
+        let mut seen = HashSet::new();
+        let mut next = head;
+        while let Some(id) = next {
+            if id >= self.next_overflow_id {
+                return Err(op::overflow_corruption(id, CorruptionKind::Missing));
+            }
+            if !seen.insert(id) {
+                return Err(op::overflow_corruption(id, CorruptionKind::InvalidData));
+            }
+            out.push(id);
+            next = self.overflow_pages.expect_page(id)?.overflow_id;
+        }
+        Ok(out)
+    }
+
+    fn write_free_overflow_ids(&self) -> Result<Option<u64>> {
+        let ids = &self.free_overflow_ids;
+        for (i, &id) in ids.iter().enumerate() {
+            let mut page = Page::new();
+            page.overflow_id = ids.get(i + 1).copied();
+            self.overflow_pages.write_page_atomic(id, page)?;
+        }
+        Ok(ids.first().copied())
+    }
+
+    fn commit_superblock(&mut self, clean: bool) -> Result<()> {
+        self.commit_superblock_with(clean, None)
+    }
+
+    fn commit_superblock_with(
+        &mut self,
+        clean: bool,
+        free_overflow_head: Option<u64>,
+    ) -> Result<()> {
+        self.write_superblock(clean, free_overflow_head)?;
+        self.main_pages.flush()?;
+        Ok(())
+    }
+
+    /// Write the superblock without flushing it.
+    fn write_superblock(&mut self, clean: bool, free_overflow_head: Option<u64>) -> Result<()> {
+        self.superblock_seq += 1;
+        let sb = Superblock {
+            seq: self.superblock_seq,
+            version: FORMAT_VERSION,
+            page_size: self.page_size,
+            hasher_id: self.hasher.id(),
+            hasher_params: self.hasher.params(),
+            clean,
+
+            main_base_level: self.main_base_level,
+            next_split_main_page_id: self.next_split_main_page_id,
+            next_overflow_id: self.next_overflow_id,
+            n_items: self.n_items,
+            n_bytes: self.n_bytes,
+            free_overflow_head,
+            journals: self.journals.iter().map(|j| j.head.clone()).collect(),
+        };
+        sb.write(&self.main_pages)?;
+        Ok(())
+    }
+
+    /// Persist all the pages and mark the table clean so the next open doesn't need to traverse the pages.
+    /// This is also done on drop but errors are ignored there.
+    pub fn close(mut self) -> Result<()> {
+        self.do_close()
+    }
+
+    fn do_close(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
         }
+        self.closed = true;
+
+        // The journals are dropped before the free pages are listed.
+        self.checkpoint()?;
+        // All the pages must be persisted before the superblock says clean.
+        self.release_overflow_pages()?;
+        let free_overflow_head = self.write_free_overflow_ids()?;
+        self.sync()?;
+        self.commit_superblock_with(true, free_overflow_head)?;
+        // The flush in the commit may be skipped by the sync policy.
+        self.main_pages.sync()?;
+        Ok(())
+    }
+
+    /// Persist all the writes so far regardless of the sync policy.
+    pub fn sync(&mut self) -> Result<()> {
+        self.overflow_pages.sync()?;
+        self.main_pages.sync()?;
+        self.last_sync = Instant::now();
+        self.drop_journals()?;
+        self.truncate_wal()
+    }
+
+    /// Persist the pages and drop the journals and the records of the redo log.
+    fn checkpoint(&mut self) -> Result<()> {
+        self.overflow_pages.flush()?;
+        self.main_pages.flush()?;
+        self.drop_journals()?;
+        self.truncate_wal()
+    }
+
+    /// Drop the journals of the batches after the pages are persisted.
+    /// The superblock is written without them and persisted with the next flush.
+    fn drop_journals(&mut self) -> Result<()> {
+        if self.journals.is_empty() {
+            return Ok(());
+        }
+        let journals = std::mem::take(&mut self.journals);
+        self.write_superblock(false, None)?;
+        // The pages are reused after the superblock is persisted.
+        for id in journals.into_iter().flat_map(|j| j.ids) {
+            self.free_overflow_id(id);
+        }
+        Ok(())
+    }
+
+    /// Replaying a batch after a crash must not undo a later write to one of its keys,
+    /// so the journal is dropped first.
+    fn drop_journals_of(&mut self, key: &[u8]) -> Result<()> {
+        if !self.journals.iter().any(|j| j.keys.contains(key)) {
+            return Ok(());
+        }
+        self.checkpoint()
+    }
+
+    /// Drop the records of the redo log. The pages must be persisted first.
+    fn truncate_wal(&mut self) -> Result<()> {
+        if let Some(wal) = &mut self.wal {
+            wal.truncate()?;
+        }
+        Ok(())
+    }
+
+    /// Log the mutation in the redo log before the pages change.
+    fn log(&mut self, record: impl FnOnce() -> op::WalRecord) -> Result<()> {
+        if self.wal.as_ref().is_some_and(|wal| wal.needs_checkpoint()) {
+            self.checkpoint()?;
+        }
+        let Some(wal) = &mut self.wal else {
+            return Ok(());
+        };
+        wal.append(&record())?;
+        if self.sync_policy.ordered() {
+            wal.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flush after a write if the sync policy says so.
+    fn sync_after_write(&mut self) -> Result<()> {
+        if self.sync_policy.is_due(self.last_sync) {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Allocates an overflow page. Freed pages are reused first.
+    fn alloc_overflow_id(&mut self) -> Result<u64> {
+        if self.free_overflow_ids.is_empty() && !self.pending_free_overflow_ids.is_empty() {
+            self.release_overflow_pages()?;
+        }
+        Ok(self.alloc_overflow_id_unflushed())
+    }
+
+    /// Allocates an overflow page without flushing to reuse the pages freed since the last flush.
+    fn alloc_overflow_id_unflushed(&mut self) -> u64 {
+        if let Some(id) = self.free_overflow_ids.pop() {
+            return id;
+        }
+
+        let id = self.next_overflow_id;
+        self.next_overflow_id += 1;
+        id
+    }
+
+    /// The page is reused after the pages which referenced it are persisted.
+    fn free_overflow_id(&mut self, id: u64) {
+        self.pending_free_overflow_ids.push(id);
+    }
+
+    fn release_overflow_pages(&mut self) -> Result<()> {
+        self.overflow_pages.flush()?;
+        self.main_pages.flush()?;
+        self.free_overflow_ids
+            .append(&mut self.pending_free_overflow_ids);
+        Ok(())
+    }
+
+    fn hash_key(&self, key: &[u8]) -> Result<u64> {
+        self.hasher.hash(key).ok_or(Error::InvalidKey)
+    }
+
+    fn calc_main_page_id(&self, key: &[u8]) -> Result<u64> {
+        let hash = self.hash_key(key)?;
+        Ok(calc_main_page_id(
+            hash,
+            self.main_base_level,
+            self.next_split_main_page_id,
+        ))
+    }
+
+    fn max_page_data_len(&self) -> usize {
+        self.main_pages.max_data_len()
+    }
+
+    fn n_main_pages(&self) -> u64 {
+        (1 << self.main_base_level) + self.next_split_main_page_id
     }
 
     fn load_factor(&self) -> f64 {
-        let n_main_pages = (1 << self.main_base_level) + self.next_split_main_page_id;
-        let max_items = n_main_pages * self.max_kv_per_page.unwrap() as u64;
-        self.n_items as f64 / max_items as f64
+        let capacity = self.n_main_pages() * self.max_page_data_len() as u64;
+        self.n_bytes as f64 / capacity as f64
+    }
+
+    /// An empty main page with an empty filter if the filters are enabled.
+    fn new_main_page(&self) -> Page {
+        let mut page = Page::new();
+        if self.filter_len > 0 {
+            page.filter = Some(vec![0; self.filter_len]);
+        }
+        page
+    }
+
+    fn write_page(&self, id: PageId, page: Page) -> Result<()> {
+        match id {
+            PageId::Main(b) => self.main_pages.write_page_atomic(b, page),
+            PageId::Overflow(id) => self.overflow_pages.write_page_atomic(id, page),
+        }
+    }
+
+    /// Flush the file of the page to order it before the next writes.
+    fn flush_page_file(&self, id: PageId) -> Result<()> {
+        match id {
+            PageId::Main(_) => self.main_pages.flush(),
+            PageId::Overflow(_) => self.overflow_pages.flush(),
+        }
     }
 
     pub fn len(&self) -> u64 {
         self.n_items
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.n_items == 0
+    }
+
+    /// Walk all the chains and report the shape of the table.
+    /// It reads every page so it is meant for monitoring, not for the hot path.
+    pub fn stats(&self) -> Result<TableStats> {
+        op::Stats { db: self }.exec()
+    }
+
+    /// Returns the counters of the page cache. All zero if the cache is disabled.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache
+            .as_ref()
+            .map(|cache| cache.stats())
+            .unwrap_or_default()
+    }
+
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         op::Get { db: self }.exec(key)
     }
 
+    /// Look up many keys at once. The keys in the same bucket share one walk of its chain.
+    /// The values are returned in the order of the keys.
+    pub fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        op::GetMany { db: self }.exec(keys)
+    }
+
+    /// Take a read-only view of the current table which isn't affected by later writes.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self)
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(self, Cursor::default())
+    }
+
+    /// Resume the iteration from the cursor taken by `Iter::cursor`.
+    pub fn iter_from(&self, cursor: Cursor) -> Iter<'_> {
+        Iter::new(self, cursor)
+    }
+
+    pub fn keys(&self) -> Keys<'_> {
+        Keys(self.iter())
+    }
+
+    pub fn values(&self) -> Values<'_> {
+        Values(self.iter())
+    }
+
     pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
-        let old = op::Insert { db: self }.exec(key, value)?;
+        self.modify(key, |_| Change::Put(value))
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.modify(key.to_vec(), |_| Change::Delete)
+    }
+
+    /// Insert the pair only if the key is absent. Returns true if it was inserted.
+    pub fn insert_if_absent(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        let old = self.modify(key, Change::put_if_absent(value))?;
+        Ok(old.is_none())
+    }
+
+    /// Set the value only if the current one is `expected`, where `None` means the key is absent.
+    /// Returns true if the value was set.
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        new: Vec<u8>,
+    ) -> Result<bool> {
+        let mut swapped = false;
+        self.modify(key, Change::swap_if(expected, new, &mut swapped))?;
+        Ok(swapped)
+    }
+
+    /// Set the value returned by `f` from the current one, or delete the key if it returns `None`.
+    /// Returns the old value.
+    pub fn update(
+        &mut self,
+        key: Vec<u8>,
+        f: impl FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.modify(key, Change::put_or_delete(f))
+    }
 
-        if self.load_factor() > 0.8 {
-            op::Split { db: self }.exec().ok();
+    /// Delete the key only if `pred` holds for its value. Returns the deleted value.
+    pub fn remove_if(
+        &mut self,
+        key: &[u8],
+        pred: impl FnOnce(&[u8]) -> bool,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut removed = false;
+        let old = self.modify(key.to_vec(), Change::delete_if(pred, &mut removed))?;
+        Ok(old.filter(|_| removed))
+    }
+
+    /// Walk the chain of the key once and apply the change decided from the current value.
+    /// Returns the old value.
+    fn modify(
+        &mut self,
+        key: Vec<u8>,
+        decide: impl FnOnce(Option<&[u8]>) -> Change,
+    ) -> Result<Option<Vec<u8>>> {
+        self.drop_journals_of(&key)?;
+        let (old, applied) = op::Modify { db: self }.exec(key, decide)?;
+
+        match applied {
+            Applied::Put if self.load_factor() > 0.8 => {
+                op::Split { db: self }.exec().ok();
+            }
+            Applied::Delete if self.load_factor() < 0.3 => {
+                op::Merge { db: self }.exec()?;
+            }
+            _ => {}
         }
 
+        self.sync_after_write()?;
         Ok(old)
     }
+}
 
-    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        op::Delete { db: self }.exec(key)
+/// The main page of the hash in the split state.
+fn calc_main_page_id(hash: u64, main_base_level: u8, next_split_main_page_id: u64) -> u64 {
+    let b = hash & ((1 << main_base_level) - 1);
+    if b < next_split_main_page_id {
+        hash & ((1 << (main_base_level + 1)) - 1)
+    } else {
+        b
+    }
+}
+
+impl ForeverHash {
+    /// Apply the puts and deletes as a unit.
+    /// Only the pages changed are rewritten, each once, and the splits needed are done afterward.
+    /// The batch is journaled first and committed by writing the superblock,
+    /// so it is either fully applied or not at all after a crash. With the redo log, it is logged instead.
+    ///
+    /// Unless the sync policy is `Never`, the batch is durable when this returns. Besides the flushes of the splits,
+    /// it takes one flush of each page file, or of the overflow page file and the redo log with the log.
+    /// The journal is dropped on the next sync or batch. Until then, the first single write
+    /// to a key of the batch flushes each page file once more.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        op::Batch { db: self }.exec(batch)
+    }
+}
+
+impl Drop for ForeverHash {
+    fn drop(&mut self) {
+        self.do_close().ok();
     }
 }
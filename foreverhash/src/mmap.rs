@@ -0,0 +1,69 @@
+use super::*;
+
+use std::os::fd::AsRawFd;
+
+/// A shared read-only mapping of a file.
+/// The mapping may be longer than the file to leave room to grow. Reading beyond the end of the file
+/// raises SIGBUS so the caller must check the length of the file.
+pub struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only and never mutated through the pointer.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    pub fn new(f: &File, len: usize) -> Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                f.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    /// Flush the range of the file to the disk.
+    pub fn sync(&self, len: usize) -> Result<()> {
+        let len = len.min(self.len);
+        if len == 0 {
+            return Ok(());
+        }
+        let r = unsafe { libc::msync(self.ptr, len, libc::MS_SYNC) };
+        if r != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+impl std::ops::Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// The length of the mapping to cover `file_len` bytes.
+/// The mapping grows by doubling so growing the file rarely remaps.
+pub fn mapping_len(file_len: u64) -> usize {
+    (file_len as usize).next_power_of_two().max(1 << 20)
+}
@@ -0,0 +1,416 @@
+use super::*;
+
+/// Puts and deletes applied by `ForeverHash::write_batch` as a unit.
+/// If the same key is written more than once, the last write wins.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: HashMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+/// A put, or a delete if the value is `None`.
+type BatchOp = (Vec<u8>, Option<Vec<u8>>);
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.ops.insert(key, None);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.ops.keys()
+    }
+
+    /// Add the ops of a later batch. Its writes win.
+    pub(crate) fn extend(&mut self, later: WriteBatch) {
+        self.ops.extend(later.ops);
+    }
+}
+
+/// The overflow pages holding a batch. Recorded in the superblock.
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Debug, Clone, PartialEq)]
+pub struct JournalHead {
+    pub puts: Option<u64>,
+    /// The deleted keys with empty values.
+    pub deletes: Option<u64>,
+    /// The checksum of the pairs. The superblock may reach the disk before the journal.
+    pub crc: u32,
+}
+
+/// A batch whose journal is in the superblock written last.
+pub struct Journal {
+    pub head: JournalHead,
+    /// The pages holding the journal.
+    pub ids: Vec<u64>,
+    /// The keys of the batch. Replaying the batch must not undo a later write to one of them.
+    pub keys: HashSet<Vec<u8>>,
+    /// The pages of the batch are persisted, so the journal is dropped with the next superblock.
+    pub persisted: bool,
+}
+
+pub struct Batch<'a> {
+    pub db: &'a mut ForeverHash,
+}
+
+impl Batch<'_> {
+    /// Journal the batch and apply it.
+    /// The journal, the new overflow pages and the superblock pointing to the journal share one flush,
+    /// which commits the batch. The pages already in the chains are rewritten after it.
+    /// With the redo log, the batch is logged as one record instead.
+    pub fn exec(self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let max_len = self.db.max_page_data_len();
+        let mut puts = Vec::new();
+        let mut deletes = Vec::new();
+        for (k, v) in batch.ops {
+            self.db.calc_main_page_id(&k)?;
+            match v {
+                Some(v) => {
+                    if !pair_fits(&k, &v, max_len) {
+                        return Err(Error::TooLarge);
+                    }
+                    puts.push((k, v));
+                }
+                // A key too large to be stored is never in the table.
+                None if pair_fits(&k, &[], max_len) => deletes.push((k, Vec::new())),
+                None => {}
+            }
+        }
+
+        // With the redo log, the batch is logged as one record instead.
+        let journal = if self.db.wal.is_some() {
+            self.db.log(|| WalRecord::Batch {
+                puts: puts.clone(),
+                deletes: deletes.iter().map(|(k, _)| k.clone()).collect(),
+            })?;
+            None
+        } else {
+            let (puts_head, mut ids) = write_journal_chain(self.db, &puts)?;
+            let (deletes_head, deletes_ids) = write_journal_chain(self.db, &deletes)?;
+            ids.extend(deletes_ids);
+            Some(Journal {
+                head: JournalHead {
+                    puts: puts_head,
+                    deletes: deletes_head,
+                    crc: journal_crc(&mut puts, &mut deletes),
+                },
+                ids,
+                keys: puts
+                    .iter()
+                    .chain(&deletes)
+                    .map(|(k, _)| k.clone())
+                    .collect(),
+                persisted: false,
+            })
+        };
+
+        let ops = puts
+            .into_iter()
+            .map(|(k, v)| (k, Some(v)))
+            .chain(deletes.into_iter().map(|(k, _)| (k, None)));
+        apply(self.db, ops, |db| match journal {
+            Some(journal) => commit_journal(db, journal),
+            None => db.overflow_pages.flush(),
+        })
+    }
+
+    /// Apply the batch read from the journal on open.
+    /// The journal is kept until the pages are persisted.
+    pub fn replay(self, batch: WriteBatch) -> Result<()> {
+        apply(self.db, batch.ops, |db| db.overflow_pages.flush())
+    }
+}
+
+/// Write the superblock pointing to the journal and persist it with the pages written before.
+fn commit_journal(db: &mut ForeverHash, journal: Journal) -> Result<()> {
+    // The batches whose pages were persisted before are dropped from the superblock.
+    // The others are kept until this flush persists their pages.
+    let (dropped, kept) = std::mem::take(&mut db.journals)
+        .into_iter()
+        .partition::<Vec<_>, _>(|j| j.persisted);
+    db.journals = kept;
+    db.journals.push(journal);
+    db.write_superblock(false, None)?;
+
+    db.overflow_pages.flush()?;
+    db.main_pages.flush()?;
+    let n = db.journals.len();
+    for j in &mut db.journals[..n - 1] {
+        j.persisted = true;
+    }
+    // Nothing on the disk refers to the pages freed before or to the dropped journals after the flush.
+    db.free_overflow_ids
+        .append(&mut db.pending_free_overflow_ids);
+    db.free_overflow_ids
+        .extend(dropped.into_iter().flat_map(|j| j.ids));
+    Ok(())
+}
+
+fn pair_fits(key: &[u8], value: &[u8], max_len: usize) -> bool {
+    let mut page = Page::new();
+    page.try_insert(key.to_vec(), value.to_vec(), max_len)
+        .is_ok()
+}
+
+/// The checksum of the puts and the deletes, sorted as the pages don't keep the order.
+fn journal_crc(puts: &mut [KvPair], deletes: &mut [KvPair]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for pairs in [puts, deletes] {
+        pairs.sort_unstable();
+        hasher.update(&(pairs.len() as u64).to_le_bytes());
+        for (k, v) in pairs.iter() {
+            hasher.update(&(k.len() as u64).to_le_bytes());
+            hasher.update(k);
+            hasher.update(&(v.len() as u64).to_le_bytes());
+            hasher.update(v);
+        }
+    }
+    hasher.finalize()
+}
+
+/// Write the pairs into newly allocated overflow pages.
+/// Returns the head of the chain and the pages.
+fn write_journal_chain(db: &mut ForeverHash, pairs: &[KvPair]) -> Result<(Option<u64>, Vec<u64>)> {
+    let max_len = db.max_page_data_len();
+    let mut pages: Vec<Page> = Vec::new();
+    for (k, v) in pairs {
+        let (k, v) = match pages.last_mut() {
+            Some(page) => match page.try_insert(k.clone(), v.clone(), max_len) {
+                Ok(_) => continue,
+                Err(pair) => pair,
+            },
+            None => (k.clone(), v.clone()),
+        };
+        // The pair fits in an empty page because it is checked before.
+        let mut page = Page::new();
+        page.insert(k, v);
+        pages.push(page);
+    }
+
+    let mut ids = Vec::new();
+    for _ in 0..pages.len() {
+        ids.push(db.alloc_overflow_id_unflushed());
+    }
+    for (i, mut page) in pages.into_iter().enumerate() {
+        page.overflow_id = ids.get(i + 1).copied();
+        db.overflow_pages.write_page_atomic(ids[i], page)?;
+    }
+
+    Ok((ids.first().copied(), ids))
+}
+
+/// Read the pairs in the journal and the pages holding them.
+/// Returns `None` if the journal didn't reach the disk, so the batch wasn't committed.
+pub fn read_journal(
+    db: &ForeverHash,
+    head: &JournalHead,
+) -> Result<Option<(WriteBatch, Vec<u64>)>> {
+    let mut pairs: [Vec<KvPair>; 2] = Default::default();
+    let mut ids = Vec::new();
+    let mut seen = HashSet::new();
+    for (mut next, pairs) in [head.puts, head.deletes].into_iter().zip(&mut pairs) {
+        while let Some(id) = next {
+            // A stale page may link back into the chain.
+            if !seen.insert(id) {
+                return Ok(None);
+            }
+            let page = match db.overflow_pages.expect_page(id) {
+                Ok(page) => page,
+                Err(Error::Corruption { .. }) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            ids.push(id);
+            next = page.overflow_id;
+            pairs.extend(page.kv_pairs);
+        }
+    }
+
+    let [mut puts, mut deletes] = pairs;
+    if journal_crc(&mut puts, &mut deletes) != head.crc {
+        return Ok(None);
+    }
+    let mut batch = WriteBatch::new();
+    for (k, v) in puts {
+        batch.put(k, v);
+    }
+    for (k, _) in deletes {
+        batch.delete(k);
+    }
+    Ok(Some((batch, ids)))
+}
+
+/// A page of a chain rewritten by a batch.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mark {
+    Clean,
+    Dirty,
+    /// A newly allocated overflow page. Nothing on the disk links to it yet.
+    New,
+}
+
+impl Mark {
+    fn touch(&mut self) {
+        if *self == Mark::Clean {
+            *self = Mark::Dirty;
+        }
+    }
+}
+
+/// Rewrite the pages changed by the ops once, then split or merge as needed.
+/// The new overflow pages are written first, then `commit` is called, then the pages already in the chains.
+/// `commit` must persist the new pages so the rewritten pages never link to a page not on the disk.
+///
+/// Applying the same ops again gives the same table, even over the pages partly rewritten,
+/// so the journal can be replayed after a crash.
+fn apply(
+    db: &mut ForeverHash,
+    ops: impl IntoIterator<Item = BatchOp>,
+    commit: impl FnOnce(&mut ForeverHash) -> Result<()>,
+) -> Result<()> {
+    let mut buckets: BTreeMap<u64, Vec<BatchOp>> = BTreeMap::new();
+    for (k, v) in ops {
+        let b = db.calc_main_page_id(&k)?;
+        buckets.entry(b).or_default().push((k, v));
+    }
+
+    let max_len = db.max_page_data_len();
+    let mut new_pages = Vec::new();
+    let mut dirty_pages = Vec::new();
+    let mut freed = Vec::new();
+    for (b, ops) in buckets {
+        let mut chain: Vec<(PageId, Page, Mark)> = Vec::new();
+        let mut next = Some(PageId::Main(b));
+        while let Some(page_id) = next {
+            let page = match page_id {
+                PageId::Main(b) => db.main_pages.expect_page(b)?,
+                PageId::Overflow(id) => db.overflow_pages.expect_page(id)?,
+            };
+            next = page.overflow_id.map(PageId::Overflow);
+            chain.push((page_id, page, Mark::Clean));
+        }
+
+        // The pages before this didn't fit a pair, so they are taken as full.
+        let mut room_from = 0;
+        for (k, v) in ops {
+            // A crash while the pages were rewritten may leave the key in two pages.
+            let mut old = None;
+            for (_, page, mark) in &mut chain {
+                if let Some(v) = page.kv_pairs.remove(&k) {
+                    old = old.or(Some(v));
+                    mark.touch();
+                }
+            }
+            if let Some(old) = &old {
+                db.n_bytes -= kv_cost(&k, old);
+                db.n_items -= 1;
+            }
+            let Some(v) = v else {
+                continue;
+            };
+            db.n_bytes += kv_cost(&k, &v);
+            db.n_items += 1;
+
+            let mut pair = Some((k, v));
+            while room_from < chain.len()
+                && let Some((k, v)) = pair.take()
+            {
+                let (_, page, mark) = &mut chain[room_from];
+                match page.try_insert(k, v, max_len) {
+                    Ok(_) => {
+                        mark.touch();
+                    }
+                    Err(p) => {
+                        pair = Some(p);
+                        room_from += 1;
+                    }
+                }
+            }
+            if let Some((k, v)) = pair {
+                // The pair fits in an empty page because it is checked before.
+                let mut page = Page::new();
+                page.insert(k, v);
+                let id = db.alloc_overflow_id_unflushed();
+                let (_, tail, mark) = chain.last_mut().unwrap();
+                tail.overflow_id = Some(id);
+                mark.touch();
+                chain.push((PageId::Overflow(id), page, Mark::New));
+            }
+        }
+
+        // Unlink the emptied overflow pages so they can be reused.
+        let mut i = 1;
+        while i < chain.len() {
+            if chain[i].2 == Mark::Dirty && chain[i].1.kv_pairs.is_empty() {
+                let (page_id, page, _) = chain.remove(i);
+                if let PageId::Overflow(id) = page_id {
+                    freed.push(id);
+                }
+                let (_, prev, mark) = &mut chain[i - 1];
+                prev.overflow_id = page.overflow_id;
+                mark.touch();
+            } else {
+                i += 1;
+            }
+        }
+
+        // The whole chain is known, so the filter is made exact.
+        if chain.iter().any(|(_, _, mark)| *mark != Mark::Clean)
+            && let Some(filter) = &chain[0].1.filter
+        {
+            let keys = chain
+                .iter()
+                .flat_map(|(_, page, _)| page.kv_pairs.keys().map(|k| k.as_slice()));
+            let filter = build_filter(filter.len(), keys);
+            if chain[0].1.filter.as_ref() != Some(&filter) {
+                chain[0].1.filter = Some(filter);
+                chain[0].2.touch();
+            }
+        }
+
+        for (page_id, page, mark) in chain {
+            match mark {
+                Mark::Clean => {}
+                Mark::Dirty => dirty_pages.push((page_id, page)),
+                Mark::New => new_pages.push((page_id, page)),
+            }
+        }
+    }
+
+    for (page_id, page) in new_pages {
+        db.write_page(page_id, page)?;
+    }
+    commit(db)?;
+    for (page_id, page) in dirty_pages {
+        db.write_page(page_id, page)?;
+    }
+    // The pages are reused after the rewritten pages are persisted.
+    for id in freed {
+        db.free_overflow_id(id);
+    }
+
+    while db.load_factor() > 0.8 {
+        Split { db }.exec()?;
+    }
+    while db.n_main_pages() > 2 && db.load_factor() < 0.3 {
+        Merge { db }.exec()?;
+    }
+
+    Ok(())
+}
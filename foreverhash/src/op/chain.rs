@@ -0,0 +1,85 @@
+use super::*;
+
+pub type KvPair = (Vec<u8>, Vec<u8>);
+pub type PageChain = VecDeque<(PageId, Page)>;
+
+/// Collect all the kv-pairs which is reachable from the main page
+/// and the ids of the overflow pages in the chain.
+pub fn collect_chain(db: &ForeverHash, b: u64) -> Result<(Vec<KvPair>, Vec<u64>)> {
+    let mut out: Vec<KvPair> = Vec::new();
+    let mut overflow_ids = Vec::new();
+
+    let mut cur_page = db.main_pages.expect_page(b)?;
+    loop {
+        for (k, v) in cur_page.kv_pairs.drain() {
+            out.push((k, v));
+        }
+
+        match cur_page.overflow_id {
+            Some(id) => {
+                overflow_ids.push(id);
+                cur_page = db.overflow_pages.expect_page(id)?;
+            }
+            None => {
+                break;
+            }
+        }
+    }
+
+    Ok((out, overflow_ids))
+}
+
+/// Pack the kv-pairs into a new chain from the main page.
+/// The overflow pages are newly allocated so the old chain is intact until the main page is written.
+pub fn build_chain(db: &mut ForeverHash, b: u64, kv_pairs: Vec<KvPair>) -> Result<PageChain> {
+    let mut page_chain = VecDeque::new();
+    page_chain.push_back((PageId::Main(b), db.new_main_page()));
+
+    for (k, v) in kv_pairs {
+        let tail = page_chain.back_mut().unwrap();
+
+        let Err((k, v)) = tail.1.try_insert(k, v, db.max_page_data_len()) else {
+            continue;
+        };
+
+        let new_overflow_id = db.alloc_overflow_id()?;
+        let tail = page_chain.back_mut().unwrap();
+        tail.1.overflow_id = Some(new_overflow_id);
+
+        // The pair fits in an empty page because it was stored in a page before.
+        let mut new_page = Page::new();
+        new_page.insert(k, v);
+
+        page_chain.push_back((PageId::Overflow(new_overflow_id), new_page));
+    }
+
+    if db.filter_len > 0 {
+        let keys = page_chain
+            .iter()
+            .flat_map(|(_, page)| page.kv_pairs.keys().map(|k| k.as_slice()));
+        let filter = build_filter(db.filter_len, keys);
+        page_chain[0].1.filter = Some(filter);
+    }
+
+    Ok(page_chain)
+}
+
+pub fn write_chain(db: &ForeverHash, page_chain: PageChain) -> Result<()> {
+    // Write from overflow pages.
+    for (page_id, page) in page_chain.into_iter().rev() {
+        match page_id {
+            PageId::Main(id) => {
+                // Before commiting the main page, ensure that overflow pages is persisted.
+                // Since split is rare, performance impact by sync call is small.
+                db.overflow_pages.flush()?;
+                db.main_pages.write_page_atomic(id, page)?;
+                // We don't need to sync the main page because losing the main page doesn't affect consistency.
+            }
+            PageId::Overflow(id) => {
+                db.overflow_pages.write_page_atomic(id, page)?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -6,22 +6,67 @@ pub struct Get<'a> {
 
 impl Get<'_> {
     pub fn exec(self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let b = self.db.calc_main_page_id(key);
-        let mut page = self.db.main_pages.read_page_ref(b)?.unwrap();
+        let b = self.db.calc_main_page_id(key)?;
+        get_in_chain(self.db, b, key)
+    }
+}
+
+/// Look up the key in the chain of the main page `b`.
+/// Used by the table, the shared handle and the snapshots.
+pub fn get_in_chain(pages: &impl Pages, b: u64, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut page = pages.page_ref(PageId::Main(b))?;
+    if !page.may_contain(key) {
+        return Ok(None);
+    }
 
-        loop {
-            if let Some(v) = page.get_value(key) {
-                return Ok(Some(v.to_owned()));
+    loop {
+        if let Some(v) = page.get_value(key) {
+            return Ok(Some(v.to_owned()));
+        }
+
+        match page.overflow_id() {
+            Some(id) => {
+                page = pages.page_ref(PageId::Overflow(id))?;
             }
+            None => {
+                return Ok(None);
+            }
+        }
+    }
+}
 
-            match page.overflow_id() {
-                Some(id) => {
-                    page = self.db.overflow_pages.read_page_ref(id)?.unwrap();
-                }
-                None => {
-                    return Ok(None);
+pub struct GetMany<'a> {
+    pub db: &'a ForeverHash,
+}
+
+impl GetMany<'_> {
+    /// Look up the keys of each bucket in one walk of its chain.
+    /// The buckets are read in the order of the main pages.
+    pub fn exec<K: AsRef<[u8]>>(self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut buckets: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+        for (i, k) in keys.iter().enumerate() {
+            let b = self.db.calc_main_page_id(k.as_ref())?;
+            buckets.entry(b).or_default().push(i);
+        }
+
+        let mut values = vec![None; keys.len()];
+        for (b, mut pending) in buckets {
+            let mut page = self.db.main_pages.expect_page_ref(b)?;
+            pending.retain(|&i| page.may_contain(keys[i].as_ref()));
+            while !pending.is_empty() {
+                pending.retain(|&i| match page.get_value(keys[i].as_ref()) {
+                    Some(v) => {
+                        values[i] = Some(v.to_owned());
+                        false
+                    }
+                    None => true,
+                });
+                match page.overflow_id() {
+                    Some(id) => page = self.db.overflow_pages.expect_page_ref(id)?,
+                    None => break,
                 }
             }
         }
+        Ok(values)
     }
 }
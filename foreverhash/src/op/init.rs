@@ -7,8 +7,12 @@ pub struct Init<'a> {
 impl Init<'_> {
     pub fn exec(self) -> Result<()> {
         // Insert two empty pages if the main pages are not initialized.
-        self.db.main_pages.write_page_atomic(0, Page::new())?;
-        self.db.main_pages.write_page_atomic(1, Page::new())?;
+        self.db
+            .main_pages
+            .write_page_atomic(0, self.db.new_main_page())?;
+        self.db
+            .main_pages
+            .write_page_atomic(1, self.db.new_main_page())?;
 
         Ok(())
     }
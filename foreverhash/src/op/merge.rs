@@ -0,0 +1,62 @@
+use super::*;
+
+pub struct Merge<'a> {
+    pub db: &'a mut ForeverHash,
+}
+
+impl Merge<'_> {
+    /// Fold the last main page into its buddy and shrink the main pages. The counterpart of `Split`.
+    pub fn exec(mut self) -> Result<()> {
+        let n_main_pages = self.db.n_main_pages();
+        // Invariant: there are at least two valid main pages.
+        if n_main_pages <= 2 {
+            return Ok(());
+        }
+
+        let (buddy_id, last_id) = self.buddy_pair();
+
+        let (mut kv_pairs, mut old_overflow_ids) = collect_chain(self.db, buddy_id)?;
+        let (last_kv_pairs, last_overflow_ids) = collect_chain(self.db, last_id)?;
+        kv_pairs.extend(last_kv_pairs);
+        old_overflow_ids.extend(last_overflow_ids);
+
+        let page_chain = build_chain(self.db, buddy_id, kv_pairs)?;
+        write_chain(self.db, page_chain)?;
+        self.db.main_pages.flush()?;
+
+        // Truncating the last main page commits the merge.
+        // If crashed before that, the pairs of the last chain remain in the buddy. They are removed by `Restore`.
+        // The buddy holds all the pairs now, so the last page is dropped from the split state
+        // even if the truncate fails.
+        self.dec_split_id();
+        self.db.main_pages.truncate(last_id)?;
+
+        for id in old_overflow_ids {
+            self.db.free_overflow_id(id);
+        }
+        self.db.release_overflow_pages()?;
+
+        Ok(())
+    }
+
+    /// Returns the main page to merge into and the last main page.
+    pub fn buddy_pair(&self) -> (u64, u64) {
+        let (split_id, level) = if self.db.next_split_main_page_id == 0 {
+            let level = self.db.main_base_level - 1;
+            (1 << level, level)
+        } else {
+            (self.db.next_split_main_page_id, self.db.main_base_level)
+        };
+
+        let buddy_id = split_id - 1;
+        (buddy_id, buddy_id + (1 << level))
+    }
+
+    fn dec_split_id(&mut self) {
+        if self.db.next_split_main_page_id == 0 {
+            self.db.main_base_level -= 1;
+            self.db.next_split_main_page_id = 1 << self.db.main_base_level;
+        }
+        self.db.next_split_main_page_id -= 1;
+    }
+}
@@ -1,19 +1,44 @@
 use super::*;
 
+mod chain;
+use chain::*;
+
 mod split;
 pub use split::Split;
 
 mod get;
-pub use get::Get;
+pub use get::{Get, GetMany, get_in_chain};
 
 mod insert;
 pub use insert::Insert;
 
+mod modify;
+pub use modify::{Applied, Change, Modify};
+
 mod restore;
-pub use restore::Restore;
+pub use restore::{Restore, overflow_corruption};
 
 mod init;
 pub use init::Init;
 
 mod delete;
 pub use delete::Delete;
+
+mod merge;
+pub use merge::Merge;
+
+mod verify;
+pub use verify::{Problem, Verify, VerifyReport};
+
+mod stats;
+pub use stats::{Stats, TableStats};
+
+mod repair;
+pub use repair::{Repair, RepairReport};
+
+mod batch;
+pub use batch::read_journal;
+pub use batch::{Batch, Journal, JournalHead, WriteBatch};
+
+mod wal;
+pub use wal::{Redo, Wal, WalRecord};
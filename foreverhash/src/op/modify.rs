@@ -0,0 +1,288 @@
+use super::*;
+
+/// The change to the pair decided from the current value.
+pub enum Change {
+    Keep,
+    Put(Vec<u8>),
+    Delete,
+}
+
+// The decisions of the conditional writes, shared by `ForeverHash` and `SharedForeverHash`.
+impl Change {
+    /// Put the value if the key is absent.
+    pub fn put_if_absent(value: Vec<u8>) -> impl FnOnce(Option<&[u8]>) -> Change {
+        |old| match old {
+            None => Change::Put(value),
+            Some(_) => Change::Keep,
+        }
+    }
+
+    /// Put `new` if the current value is `expected`. Sets `swapped` if it does.
+    pub fn swap_if(
+        expected: Option<&[u8]>,
+        new: Vec<u8>,
+        swapped: &mut bool,
+    ) -> impl FnOnce(Option<&[u8]>) -> Change {
+        move |old| {
+            if old != expected {
+                return Change::Keep;
+            }
+            *swapped = true;
+            Change::Put(new)
+        }
+    }
+
+    /// Put the value returned by `f`, or delete the key if it returns `None`.
+    pub fn put_or_delete(
+        f: impl FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> impl FnOnce(Option<&[u8]>) -> Change {
+        |old| match f(old) {
+            Some(v) => Change::Put(v),
+            None => Change::Delete,
+        }
+    }
+
+    /// Delete the key if `pred` holds for its value. Sets `removed` if it does.
+    pub fn delete_if(
+        pred: impl FnOnce(&[u8]) -> bool,
+        removed: &mut bool,
+    ) -> impl FnOnce(Option<&[u8]>) -> Change {
+        move |old| match old {
+            Some(v) if pred(v) => {
+                *removed = true;
+                Change::Delete
+            }
+            _ => Change::Keep,
+        }
+    }
+}
+
+/// The change actually made to the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Applied {
+    Nothing,
+    Put,
+    Delete,
+}
+
+pub struct Modify<'a> {
+    pub db: &'a mut ForeverHash,
+}
+
+impl Modify<'_> {
+    /// Walk the chain once to find the current value, then rewrite the pages as `decide` says.
+    /// Returns the old value.
+    pub fn exec(
+        self,
+        key: Vec<u8>,
+        decide: impl FnOnce(Option<&[u8]>) -> Change,
+    ) -> Result<(Option<Vec<u8>>, Applied)> {
+        let b = self.db.calc_main_page_id(&key)?;
+
+        // The pages from the main page to the page which holds the key, or to the tail.
+        let mut chain: Vec<(PageId, Page)> = Vec::new();
+        let mut holder_at = None;
+
+        let mut next = Some(PageId::Main(b));
+        while let Some(page_id) = next {
+            let page = self.read_page(page_id)?;
+            next = page.overflow_id.map(PageId::Overflow);
+
+            let found = page.contains(&key);
+            let filtered_out = !page.may_contain(&key);
+            chain.push((page_id, page));
+            // The key is unique in the chain so we can stop here.
+            if found {
+                holder_at = Some(chain.len() - 1);
+                break;
+            }
+            // The filter of the main page says the key isn't in the chain.
+            if filtered_out {
+                break;
+            }
+        }
+
+        let old = holder_at.and_then(|i| chain[i].1.kv_pairs.get(&key).cloned());
+        match decide(old.as_deref()) {
+            Change::Keep => Ok((old, Applied::Nothing)),
+            Change::Put(value) => {
+                self.db.log(|| WalRecord::Insert {
+                    key: key.clone(),
+                    value: value.clone(),
+                })?;
+                self.put(chain, holder_at, next, key, value)?;
+                Ok((old, Applied::Put))
+            }
+            Change::Delete => {
+                let Some(i) = holder_at else {
+                    return Ok((None, Applied::Nothing));
+                };
+                self.db.log(|| WalRecord::Delete { key: key.clone() })?;
+                self.delete(chain, i, &key)?;
+                Ok((old, Applied::Delete))
+            }
+        }
+    }
+
+    fn read_page(&self, page_id: PageId) -> Result<Page> {
+        match page_id {
+            PageId::Main(b) => self.db.main_pages.expect_page(b),
+            PageId::Overflow(id) => self.db.overflow_pages.expect_page(id),
+        }
+    }
+
+    /// `next` is the page after the walked pages.
+    fn put(
+        self,
+        mut chain: Vec<(PageId, Page)>,
+        holder_at: Option<usize>,
+        mut next: Option<PageId>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<()> {
+        let cost = kv_cost(&key, &value);
+        let max_len = self.db.max_page_data_len();
+
+        let probe = key.clone();
+        let mut pair = Some((key, value));
+        let mut old = None;
+        let add_to_filter = !chain[0].1.may_contain(&probe);
+
+        // Pages are filled by the encoded size so the pair doesn't always fit in the page
+        // which holds the old value. In that case, the pair is moved to another page.
+        // The index of the page the pair is inserted into.
+        let mut placed_at = None;
+        for (i, (_, page)) in chain.iter_mut().enumerate() {
+            if let Some((k, v)) = pair.take() {
+                match page.try_insert(k, v, max_len) {
+                    Ok(o) => {
+                        old = o;
+                        placed_at = Some(i);
+                    }
+                    Err(p) => pair = Some(p),
+                }
+            }
+            if Some(i) == holder_at && placed_at != Some(i) {
+                old = page.kv_pairs.remove(&probe);
+            }
+        }
+
+        // The pair didn't fit in the pages up to the holder. Continue the walk.
+        while pair.is_some()
+            && let Some(page_id) = next
+        {
+            let mut page = self.read_page(page_id)?;
+            next = page.overflow_id.map(PageId::Overflow);
+
+            let (k, v) = pair.take().unwrap();
+            match page.try_insert(k, v, max_len) {
+                Ok(_) => placed_at = Some(chain.len()),
+                Err(p) => pair = Some(p),
+            }
+            chain.push((page_id, page));
+        }
+
+        // Write the page which gains the pair before the page which loses the old pair.
+        // On crash, the old pair may remain but it is found after the new one.
+        let mut dirty = Vec::new();
+        dirty.extend(placed_at);
+        // The page which makes the new pair reachable.
+        let mut gained_at = placed_at;
+
+        if let Some((k, v)) = pair {
+            // If no page has room, allocate a new overflow page.
+            let mut new_page = Page::new();
+            if new_page.try_insert(k, v, max_len).is_err() {
+                return Err(Error::TooLarge);
+            }
+
+            let new_overflow_id = self.db.alloc_overflow_id()?;
+            self.db
+                .overflow_pages
+                .write_page_atomic(new_overflow_id, new_page)?;
+            // Since sync is only happened when we allocate a new overflow page and it is rare,
+            // the performance impact is small.
+            self.db.overflow_pages.flush()?;
+
+            // After writing the new overflow page, update the old tail page.
+            let tail_at = chain.len() - 1;
+            chain[tail_at].1.overflow_id = Some(new_overflow_id);
+            dirty.push(tail_at);
+            gained_at = Some(tail_at);
+        }
+
+        if let Some(i) = holder_at
+            && !dirty.contains(&i)
+        {
+            dirty.push(i);
+        }
+
+        // The filter must have the key before the pair is reachable,
+        // so the main page is written first unless it is written with the pair or the new link.
+        // The writes may still be reordered by the OS, so `Restore` rebuilds the filters after a crash.
+        if add_to_filter {
+            filter_add(chain[0].1.filter.as_mut().unwrap(), &probe);
+            if !dirty.contains(&0) {
+                dirty.insert(0, 0);
+            }
+        }
+
+        let mut chain: Vec<Option<(PageId, Page)>> = chain.into_iter().map(Some).collect();
+        let gained_id = gained_at.map(|i| chain[i].as_ref().unwrap().0);
+        for i in dirty {
+            let (page_id, page) = chain[i].take().unwrap();
+            // The pair moved to another page. Both pages must not be written out of order,
+            // or the pair is lost if the old page reaches the disk alone.
+            if Some(i) == holder_at
+                && gained_at != holder_at
+                && let Some(gained_id) = gained_id
+            {
+                self.db.flush_page_file(gained_id)?;
+            }
+            self.db.write_page(page_id, page)?;
+        }
+
+        match &old {
+            Some(old) => {
+                self.db.n_bytes -= kv_cost(&probe, old);
+            }
+            None => {
+                self.db.n_items += 1;
+            }
+        }
+        self.db.n_bytes += cost;
+
+        Ok(())
+    }
+
+    /// Remove the key from the page at `holder_at`.
+    fn delete(self, mut chain: Vec<(PageId, Page)>, holder_at: usize, key: &[u8]) -> Result<()> {
+        let (page_id, mut page) = chain.pop().unwrap();
+        debug_assert_eq!(chain.len(), holder_at);
+
+        let removed = page.kv_pairs.remove(key);
+        // Bits can't be removed from the filter unless the whole chain is known.
+        // Otherwise the filter gets exact again when the chain is split or merged.
+        if page.overflow_id.is_none()
+            && let Some(filter) = &mut page.filter
+        {
+            *filter = build_filter(filter.len(), page.kv_pairs.keys().map(|k| k.as_slice()));
+        }
+        match page_id {
+            // Unlink the emptied overflow page from the chain so it can be reused.
+            PageId::Overflow(id) if page.kv_pairs.is_empty() => {
+                let (prev_id, mut prev_page) = chain.pop().unwrap();
+                prev_page.overflow_id = page.overflow_id;
+                self.db.write_page(prev_id, prev_page)?;
+                self.db.free_overflow_id(id);
+            }
+            _ => self.db.write_page(page_id, page)?,
+        }
+
+        if let Some(v) = &removed {
+            self.db.n_items -= 1;
+            self.db.n_bytes -= kv_cost(key, v);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use super::*;
+
+use restore::calc_base_level;
+
+/// The result of `ForeverHash::repair`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RepairReport {
+    /// The number of pairs in the new table.
+    pub n_items: u64,
+    /// The pairs in the new table which were only found in overflow pages not reachable from any main page.
+    /// They are only taken for the buckets whose chain couldn't be read in full, and may be stale.
+    pub n_orphaned_items: u64,
+    /// The copies dropped because another copy of the key was found later.
+    pub n_duplicates: u64,
+    /// The pairs dropped because the key can't be hashed by the hasher.
+    pub n_invalid_keys: u64,
+    /// The pages which couldn't be read and the broken links. The pairs in them are lost.
+    pub lost: Vec<Problem>,
+}
+
+/// Copy all the readable pairs into a new table.
+pub struct Repair<'a> {
+    pub src: &'a mut ForeverHash,
+    pub dst: &'a mut ForeverHash,
+    /// The batch journaled in the source and the pages holding it.
+    pub journal: Option<(WriteBatch, Vec<u64>)>,
+}
+
+impl Repair<'_> {
+    pub fn exec(mut self) -> Result<RepairReport> {
+        let mut report = RepairReport::default();
+
+        let n_main_pages = self.src.main_pages.n_written_pages()?;
+        let n_overflow_pages = self.src.overflow_pages.n_pages()?;
+
+        // The overflow pages of each chain in order.
+        let mut chains = Vec::new();
+        // The buckets whose chain couldn't be read in full.
+        let mut incomplete = HashSet::new();
+        let mut reachable = vec![false; n_overflow_pages as usize];
+        for i in 0..n_main_pages {
+            let Some(page) = self.read_page(PageId::Main(i), Some(&mut report))? else {
+                chains.push(None);
+                incomplete.insert(i);
+                continue;
+            };
+
+            let mut chain = Vec::new();
+            let mut next = page.overflow_id;
+            while let Some(id) = next {
+                match reachable.get_mut(id as usize) {
+                    // Already in this or another chain. Either way the pages are copied once.
+                    Some(true) => break,
+                    Some(r) => *r = true,
+                    None => {
+                        report.lost.push(Problem::DanglingOverflowId {
+                            main_page_id: Some(i),
+                            overflow_id: id,
+                        });
+                        incomplete.insert(i);
+                        break;
+                    }
+                }
+                chain.push(id);
+                let Some(page) = self.read_page(PageId::Overflow(id), Some(&mut report))? else {
+                    incomplete.insert(i);
+                    break;
+                };
+                next = page.overflow_id;
+            }
+            chains.push(Some(chain));
+        }
+
+        // Orphaned pages hold the pairs of the broken chains but also the deleted and stale pairs
+        // of the freed pages. So they are only taken for the broken chains.
+        if n_main_pages >= 2 {
+            let (next_split_main_page_id, main_base_level) = calc_base_level(n_main_pages);
+            self.src.main_base_level = main_base_level;
+            self.src.next_split_main_page_id = next_split_main_page_id;
+        }
+        // The last copy found wins. Orphaned pages go first because they are the oldest.
+        let (batch, journal_ids) = self.journal.take().unwrap_or_default();
+        let mut orphaned = HashMap::new();
+        for id in 0..n_overflow_pages {
+            if reachable[id as usize] || journal_ids.contains(&id) || incomplete.is_empty() {
+                continue;
+            }
+            let Some(mut page) = self.read_page(PageId::Overflow(id), Some(&mut report))? else {
+                continue;
+            };
+            page.kv_pairs.retain(|k, _| {
+                self.src
+                    .calc_main_page_id(k)
+                    .is_ok_and(|b| incomplete.contains(&b))
+            });
+            orphaned.extend(page.kv_pairs.iter().map(|(k, v)| (k.clone(), v.clone())));
+            self.copy_pairs(page, &mut report)?;
+        }
+
+        // Insert writes the new pair closer to the main page than the old one,
+        // so the chain is copied from the tail.
+        for (i, chain) in chains.into_iter().enumerate() {
+            let Some(chain) = chain else {
+                continue;
+            };
+            let pages = chain
+                .into_iter()
+                .rev()
+                .map(PageId::Overflow)
+                .chain([PageId::Main(i as u64)]);
+            for page_id in pages {
+                // The broken pages are already reported.
+                if let Some(page) = self.read_page(page_id, None)? {
+                    self.copy_pairs(page, &mut report)?;
+                }
+            }
+        }
+
+        // The batch may be partly applied in the source.
+        self.dst.write_batch(batch)?;
+
+        // Only count the orphaned pairs not overwritten by the chains or the batch.
+        for (k, v) in orphaned {
+            if self.dst.get(&k)?.as_ref() == Some(&v) {
+                report.n_orphaned_items += 1;
+            }
+        }
+        report.n_items = self.dst.len();
+        Ok(report)
+    }
+
+    /// Returns `None` if the page can't be read.
+    fn read_page(
+        &self,
+        page_id: PageId,
+        report: Option<&mut RepairReport>,
+    ) -> Result<Option<Page>> {
+        let r = match page_id {
+            PageId::Main(b) => self.src.main_pages.expect_page(b),
+            PageId::Overflow(id) => self.src.overflow_pages.expect_page(id),
+        };
+        match r {
+            Ok(page) => Ok(Some(page)),
+            Err(Error::Corruption {
+                file,
+                page_id,
+                kind,
+            }) => {
+                // The slots never written are not a loss. They are past the end of the table
+                // or the overflow pages allocated but not used yet.
+                if let Some(report) = report
+                    && kind != CorruptionKind::Missing
+                {
+                    report.lost.push(Problem::CorruptedPage {
+                        file,
+                        page_id,
+                        kind,
+                    });
+                }
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn copy_pairs(&mut self, page: Page, report: &mut RepairReport) -> Result<()> {
+        for (k, v) in page.kv_pairs {
+            match self.dst.insert(k, v) {
+                Ok(Some(_)) => report.n_duplicates += 1,
+                Ok(None) => {}
+                Err(Error::InvalidKey) => report.n_invalid_keys += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
@@ -13,58 +13,110 @@ impl Restore<'_> {
         }
 
         let (next_split_main_page_id, main_base_level) = calc_base_level(n_main_pages);
-        let next_overflow_id = self.traverse_overflow_pages()?;
-        let n_items = self.traverse_all_pages(n_main_pages)?;
-
         self.db.main_base_level = main_base_level;
         self.db.next_split_main_page_id = next_split_main_page_id;
+
+        let next_overflow_id = self.traverse_overflow_pages()?;
+        let (n_items, n_bytes, free_overflow_ids) =
+            self.traverse_all_pages(n_main_pages, next_overflow_id)?;
+
         self.db.next_overflow_id = next_overflow_id;
         self.db.n_items = n_items;
+        self.db.n_bytes = n_bytes;
+        self.db.free_overflow_ids = free_overflow_ids;
+        self.db.pending_free_overflow_ids.clear();
 
         Ok(n_main_pages)
     }
 
-    /// Returns the number of valid main pages
+    /// Returns the number of main pages.
+    /// A broken page is reported as corruption while the chains are traversed.
     fn traverse_main_pages(&self) -> Result<u64> {
-        for i in 0.. {
-            let Some(_) = self.db.main_pages.read_page(i)? else {
-                return Ok(i);
-            };
-        }
-
-        unreachable!()
+        self.db.main_pages.n_written_pages()
     }
 
-    /// Returns `next_overflow_id`
+    /// Returns `next_overflow_id`.
+    /// Any page in the file can be unreadable after a crash, so this doesn't stop at the first one.
     fn traverse_overflow_pages(&self) -> Result<u64> {
-        for i in 0.. {
-            let Some(_) = self.db.overflow_pages.read_page(i)? else {
-                return Ok(i);
-            };
-        }
-
-        unreachable!()
+        self.db.overflow_pages.n_pages()
     }
 
-    /// Returns `n_items`
-    fn traverse_all_pages(&self, main_page_until: u64) -> Result<u64> {
+    /// Returns `n_items`, `n_bytes` and the overflow pages not reachable from any main page.
+    ///
+    /// The pairs left by the operation interrupted by the crash are removed on the way:
+    /// the pairs in the wrong chain (`Split` or `Merge`) and the second pair with the same key in a chain (`Insert`).
+    /// The filters missing keys of their chain, if the main page was written after its pair, are rebuilt.
+    fn traverse_all_pages(
+        &self,
+        main_page_until: u64,
+        overflow_page_until: u64,
+    ) -> Result<(u64, u64, Vec<u64>)> {
         let mut n_items = 0;
+        let mut n_bytes = 0;
+        let mut reachable = vec![false; overflow_page_until as usize];
 
         for i in 0..main_page_until {
-            let mut cur_page = self.db.main_pages.read_page(i)?.unwrap();
+            let mut next = Some(PageId::Main(i));
+            let mut seen = HashSet::new();
+            let mut filter = None;
+
+            while let Some(page_id) = next {
+                let mut page = match page_id {
+                    PageId::Main(b) => self.db.main_pages.expect_page(b)?,
+                    PageId::Overflow(id) => {
+                        // The link is broken if the page is beyond the file or already in a chain.
+                        match reachable.get_mut(id as usize) {
+                            Some(r) if !*r => *r = true,
+                            Some(_) => {
+                                return Err(overflow_corruption(id, CorruptionKind::InvalidData));
+                            }
+                            None => return Err(overflow_corruption(id, CorruptionKind::Missing)),
+                        }
+                        self.db.overflow_pages.expect_page(id)?
+                    }
+                };
+                next = page.overflow_id.map(PageId::Overflow);
+                if let PageId::Main(_) = page_id {
+                    filter = page.filter.clone();
+                }
+
+                let n = page.kv_pairs.len();
+                page.kv_pairs.retain(|k, _| {
+                    self.db.calc_main_page_id(k).ok() == Some(i) && seen.insert(k.clone())
+                });
 
-            loop {
-                n_items += cur_page.kv_pairs.len() as u64;
+                n_items += page.kv_pairs.len() as u64;
+                for (k, v) in &page.kv_pairs {
+                    n_bytes += kv_cost(k, v);
+                }
 
-                if let Some(overflow_id) = cur_page.overflow_id {
-                    cur_page = self.db.overflow_pages.read_page(overflow_id)?.unwrap();
-                } else {
-                    break;
+                if page.kv_pairs.len() < n {
+                    self.db.write_page(page_id, page)?;
                 }
             }
+
+            if let Some(bits) = filter
+                && !seen.iter().all(|k| filter_contains(&bits, k))
+            {
+                let mut page = self.db.main_pages.expect_page(i)?;
+                page.filter = Some(build_filter(bits.len(), seen.iter().map(|k| k.as_slice())));
+                self.db.main_pages.write_page_atomic(i, page)?;
+            }
         }
 
-        Ok(n_items)
+        let free_overflow_ids = (0..overflow_page_until)
+            .filter(|&id| !reachable[id as usize])
+            .collect();
+
+        Ok((n_items, n_bytes, free_overflow_ids))
+    }
+}
+
+pub fn overflow_corruption(id: u64, kind: CorruptionKind) -> Error {
+    Error::Corruption {
+        file: PageFile::Overflow,
+        page_id: id,
+        kind,
     }
 }
 
@@ -0,0 +1,96 @@
+use super::*;
+
+/// The result of `ForeverHash::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableStats {
+    pub n_items: u64,
+    pub n_main_pages: u64,
+    pub main_base_level: u8,
+    /// The main page split next.
+    pub next_split_main_page_id: u64,
+    /// The overflow pages allocated in the file.
+    pub n_overflow_pages: u64,
+    /// The overflow pages in the chains.
+    pub n_used_overflow_pages: u64,
+    /// The overflow pages freed for reuse.
+    pub n_free_overflow_pages: u64,
+    /// The overflow pages holding the journals of the last batches.
+    pub n_journal_overflow_pages: u64,
+    /// The overflow pages referenced from nowhere. Reclaimed when a dirty table is opened.
+    pub n_leaked_overflow_pages: u64,
+    /// `chain_lengths[n]` is the number of the buckets with `n` overflow pages.
+    pub chain_lengths: Vec<u64>,
+    /// The encoded bytes of the main pages and the overflow pages in the chains.
+    pub bytes_used: u64,
+    /// The capacity of the main pages and the overflow pages in the chains.
+    pub bytes_allocated: u64,
+    /// The estimated bytes of the pairs over the capacity of the main pages.
+    /// A split is done above 0.8 and a merge below 0.3.
+    pub load_factor: f64,
+}
+
+impl TableStats {
+    /// The average fill of the pages in use, from 0 to 1.
+    pub fn avg_page_fill(&self) -> f64 {
+        if self.bytes_allocated == 0 {
+            return 0.0;
+        }
+        self.bytes_used as f64 / self.bytes_allocated as f64
+    }
+
+    /// The number of overflow pages in the longest chain.
+    pub fn max_chain_length(&self) -> usize {
+        self.chain_lengths.len().saturating_sub(1)
+    }
+}
+
+/// Walk all the chains to collect the statistics. Nothing is written.
+pub struct Stats<'a> {
+    pub db: &'a ForeverHash,
+}
+
+impl Stats<'_> {
+    pub fn exec(self) -> Result<TableStats> {
+        let db = self.db;
+        let max_len = db.max_page_data_len() as u64;
+
+        let mut stats = TableStats {
+            n_items: db.n_items,
+            n_main_pages: db.n_main_pages(),
+            main_base_level: db.main_base_level,
+            next_split_main_page_id: db.next_split_main_page_id,
+            n_overflow_pages: db.next_overflow_id,
+            n_free_overflow_pages: (db.free_overflow_ids.len() + db.pending_free_overflow_ids.len())
+                as u64,
+            n_journal_overflow_pages: db.journals.iter().map(|j| j.ids.len() as u64).sum(),
+            load_factor: db.load_factor(),
+            ..Default::default()
+        };
+
+        for b in 0..stats.n_main_pages {
+            let mut page = db.main_pages.expect_page_ref(b)?;
+            stats.bytes_used += page.data_range.len() as u64;
+
+            let mut n = 0;
+            while let Some(id) = page.overflow_id() {
+                page = db.overflow_pages.expect_page_ref(id)?;
+                stats.bytes_used += page.data_range.len() as u64;
+                n += 1;
+            }
+
+            if stats.chain_lengths.len() <= n {
+                stats.chain_lengths.resize(n + 1, 0);
+            }
+            stats.chain_lengths[n] += 1;
+            stats.n_used_overflow_pages += n as u64;
+        }
+
+        stats.bytes_allocated = (stats.n_main_pages + stats.n_used_overflow_pages) * max_len;
+        stats.n_leaked_overflow_pages = stats.n_overflow_pages.saturating_sub(
+            stats.n_used_overflow_pages
+                + stats.n_free_overflow_pages
+                + stats.n_journal_overflow_pages,
+        );
+        Ok(stats)
+    }
+}
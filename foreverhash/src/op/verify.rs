@@ -0,0 +1,245 @@
+use super::*;
+
+use restore::calc_base_level;
+
+/// A problem found by `ForeverHash::verify`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// No valid superblock is found.
+    MissingSuperblock,
+    /// The page can't be read.
+    CorruptedPage {
+        file: PageFile,
+        page_id: u64,
+        kind: CorruptionKind,
+    },
+    /// The key belongs to another bucket in the current split state, or can't be hashed.
+    MisplacedKey { main_page_id: u64, key: Vec<u8> },
+    /// The key is in the chain but not in the filter of the main page, so lookups miss it.
+    MissingFromFilter { main_page_id: u64, key: Vec<u8> },
+    /// The key appears more than once in the chain.
+    DuplicateKey { main_page_id: u64, key: Vec<u8> },
+    /// The chain comes back to an overflow page already in the chain.
+    /// `main_page_id` is `None` for the free list.
+    OverflowCycle {
+        main_page_id: Option<u64>,
+        overflow_id: u64,
+    },
+    /// The overflow id points beyond the overflow page file.
+    DanglingOverflowId {
+        main_page_id: Option<u64>,
+        overflow_id: u64,
+    },
+    /// The overflow page is in two chains.
+    SharedOverflowPage {
+        overflow_id: u64,
+        main_page_ids: (Option<u64>, Option<u64>),
+    },
+    /// The overflow page is neither in a chain nor in the free list of a cleanly closed table.
+    LeakedOverflowPage { overflow_id: u64 },
+    /// The number of items in the superblock doesn't match the pages.
+    ItemCountMismatch { recorded: u64, counted: u64 },
+}
+
+/// The result of `ForeverHash::verify`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    /// True if the table was closed cleanly.
+    pub clean: bool,
+    pub n_main_pages: u64,
+    pub n_overflow_pages: u64,
+    /// The overflow pages in the chains.
+    pub n_used_overflow_pages: u64,
+    /// The overflow pages in the free list.
+    pub n_free_overflow_pages: u64,
+    /// The overflow pages in no chain. They are reclaimed on the next open if the table is dirty.
+    pub n_unreferenced_overflow_pages: u64,
+    /// The number of the valid pairs.
+    pub n_items: u64,
+    pub n_bytes: u64,
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check all the pages without writing anything.
+pub struct Verify<'a> {
+    pub db: &'a mut ForeverHash,
+}
+
+impl Verify<'_> {
+    pub fn exec(self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+
+        let sb = Superblock::read(&self.db.main_pages)?;
+        if let Some(sb) = &sb {
+            self.db.check_superblock(sb)?;
+        } else {
+            report.problems.push(Problem::MissingSuperblock);
+        }
+        // The fields in the superblock are only valid if the table was closed cleanly.
+        let clean_sb = sb.filter(|sb| sb.clean);
+        let clean = clean_sb.is_some();
+        report.clean = clean;
+
+        let n_main_pages = match &clean_sb {
+            Some(sb) => (1 << sb.main_base_level) + sb.next_split_main_page_id,
+            None => self.db.main_pages.n_written_pages()?,
+        };
+        if n_main_pages >= 2 {
+            let (next_split_main_page_id, main_base_level) = calc_base_level(n_main_pages);
+            self.db.main_base_level = main_base_level;
+            self.db.next_split_main_page_id = next_split_main_page_id;
+        }
+        report.n_main_pages = n_main_pages;
+
+        let n_overflow_pages = self.db.overflow_pages.n_pages()?;
+        report.n_overflow_pages = n_overflow_pages;
+
+        // The chain holding each overflow page. `Some(None)` is the free list.
+        let mut owners: Vec<Option<Option<u64>>> = vec![None; n_overflow_pages as usize];
+
+        if let Some(sb) = &clean_sb {
+            let free = self.walk_chain(None, sb.free_overflow_head, &mut owners, &mut report)?;
+            report.n_free_overflow_pages = free.len() as u64;
+        }
+
+        for i in 0..n_main_pages {
+            let Some(page) = self.read_page(PageId::Main(i), &mut report)? else {
+                continue;
+            };
+
+            let filter = page.filter.clone();
+            let mut pages = vec![page];
+            let next = pages[0].overflow_id;
+            let chain = self.walk_chain(Some(i), next, &mut owners, &mut report)?;
+            report.n_used_overflow_pages += chain.len() as u64;
+            pages.extend(chain);
+
+            let mut seen = HashSet::new();
+            for page in pages {
+                for (k, v) in page.kv_pairs {
+                    if self.db.calc_main_page_id(&k).ok() != Some(i) {
+                        report.problems.push(Problem::MisplacedKey {
+                            main_page_id: i,
+                            key: k,
+                        });
+                    } else if !seen.insert(k.clone()) {
+                        report.problems.push(Problem::DuplicateKey {
+                            main_page_id: i,
+                            key: k,
+                        });
+                    } else {
+                        report.n_items += 1;
+                        report.n_bytes += kv_cost(&k, &v);
+                        if filter
+                            .as_ref()
+                            .is_some_and(|bits| !filter_contains(bits, &k))
+                        {
+                            report.problems.push(Problem::MissingFromFilter {
+                                main_page_id: i,
+                                key: k,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        for (id, owner) in owners.iter().enumerate() {
+            if owner.is_none() {
+                report.n_unreferenced_overflow_pages += 1;
+                if clean {
+                    report.problems.push(Problem::LeakedOverflowPage {
+                        overflow_id: id as u64,
+                    });
+                }
+            }
+        }
+
+        if let Some(sb) = &clean_sb
+            && sb.n_items != report.n_items
+        {
+            report.problems.push(Problem::ItemCountMismatch {
+                recorded: sb.n_items,
+                counted: report.n_items,
+            });
+        }
+
+        Ok(report)
+    }
+
+    /// Returns `None` if the page is corrupted.
+    fn read_page(&self, page_id: PageId, report: &mut VerifyReport) -> Result<Option<Page>> {
+        let r = match page_id {
+            PageId::Main(b) => self.db.main_pages.expect_page(b),
+            PageId::Overflow(id) => self.db.overflow_pages.expect_page(id),
+        };
+        match r {
+            Ok(page) => Ok(Some(page)),
+            Err(Error::Corruption {
+                file,
+                page_id,
+                kind,
+            }) => {
+                report.problems.push(Problem::CorruptedPage {
+                    file,
+                    page_id,
+                    kind,
+                });
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Walk the overflow pages from `next` and returns the pages read.
+    /// `owner` is the main page of the chain or `None` for the free list.
+    fn walk_chain(
+        &self,
+        owner: Option<u64>,
+        mut next: Option<u64>,
+        owners: &mut [Option<Option<u64>>],
+        report: &mut VerifyReport,
+    ) -> Result<Vec<Page>> {
+        let mut out = Vec::new();
+        let mut visited = HashSet::new();
+
+        while let Some(id) = next {
+            if !visited.insert(id) {
+                report.problems.push(Problem::OverflowCycle {
+                    main_page_id: owner,
+                    overflow_id: id,
+                });
+                break;
+            }
+            let Some(slot) = owners.get_mut(id as usize) else {
+                report.problems.push(Problem::DanglingOverflowId {
+                    main_page_id: owner,
+                    overflow_id: id,
+                });
+                break;
+            };
+            if let Some(other) = *slot {
+                report.problems.push(Problem::SharedOverflowPage {
+                    overflow_id: id,
+                    main_page_ids: (other, owner),
+                });
+                break;
+            }
+            *slot = Some(owner);
+
+            let Some(page) = self.read_page(PageId::Overflow(id), report)? else {
+                break;
+            };
+            next = page.overflow_id;
+            out.push(page);
+        }
+
+        Ok(out)
+    }
+}
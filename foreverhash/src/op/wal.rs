@@ -0,0 +1,205 @@
+use super::*;
+
+/// The log is checkpointed when it grows beyond this.
+const CHECKPOINT_BYTES: u64 = 4 << 20;
+
+// Record: crc (4) | data len (4) | lsn (8) | data
+// The crc covers the lsn and the data.
+const HEADER_LEN: usize = 16;
+
+/// A mutation logged before the pages change.
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Debug, Clone, PartialEq)]
+pub enum WalRecord {
+    Insert {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    Split {
+        new_main_page_id: u64,
+    },
+    Batch {
+        puts: Vec<KvPair>,
+        deletes: Vec<Vec<u8>>,
+    },
+}
+
+/// The redo log. The records since the last checkpoint are applied again on open.
+pub struct Wal {
+    store: Box<dyn BlockIo>,
+    /// The end of the valid records.
+    cursor: u64,
+    /// The lsn of the next record. Not reset by truncation
+    /// so the stale records beyond a new one are never taken as valid.
+    lsn: u64,
+}
+
+impl Wal {
+    /// Open the log and read the records until the first invalid one.
+    pub fn open(store: Box<dyn BlockIo>) -> Result<(Self, Vec<WalRecord>)> {
+        let len = store.len()?;
+        let mut records = Vec::new();
+        let mut cursor = 0;
+        let mut lsn = None;
+
+        while cursor + HEADER_LEN as u64 <= len {
+            let mut header = [0u8; HEADER_LEN];
+            store.read_at(&mut header, cursor)?;
+            let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let data_len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+            let rec_lsn = u64::from_le_bytes(header[8..16].try_into().unwrap());
+            if data_len == 0 || cursor + HEADER_LEN as u64 + data_len > len {
+                break;
+            }
+            if lsn.is_some_and(|lsn| rec_lsn != lsn) {
+                break;
+            }
+
+            let mut data = vec![0u8; data_len as usize];
+            store.read_at(&mut data, cursor + HEADER_LEN as u64)?;
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&header[8..16]);
+            hasher.update(&data);
+            if hasher.finalize() != crc {
+                break;
+            }
+            let Ok(record) = rkyv::from_bytes::<WalRecord, rkyv::rancor::Error>(&data) else {
+                break;
+            };
+
+            records.push(record);
+            cursor += HEADER_LEN as u64 + data_len;
+            lsn = Some(rec_lsn + 1);
+        }
+
+        let wal = Self {
+            store,
+            cursor,
+            lsn: lsn.unwrap_or(0),
+        };
+        Ok((wal, records))
+    }
+
+    pub fn append(&mut self, record: &WalRecord) -> Result<()> {
+        let data = rkyv::to_bytes::<rkyv::rancor::Error>(record).unwrap();
+        let lsn = self.lsn.to_le_bytes();
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&lsn);
+        hasher.update(&data);
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + data.len());
+        buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&lsn);
+        buf.extend_from_slice(&data);
+
+        self.store.write_at(&buf, self.cursor)?;
+        self.cursor += buf.len() as u64;
+        self.lsn += 1;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.store.flush()?;
+        Ok(())
+    }
+
+    /// Drop all the records. The pages must be persisted before.
+    pub fn truncate(&mut self) -> Result<()> {
+        if self.cursor == 0 {
+            return Ok(());
+        }
+        self.store.set_len(0)?;
+        self.store.flush()?;
+        self.cursor = 0;
+        Ok(())
+    }
+
+    pub fn needs_checkpoint(&self) -> bool {
+        self.cursor > CHECKPOINT_BYTES
+    }
+}
+
+/// Apply the records of the log again after `Restore`.
+pub struct Redo<'a> {
+    pub db: &'a mut ForeverHash,
+}
+
+impl Redo<'_> {
+    pub fn exec(self, records: Vec<WalRecord>) -> Result<()> {
+        for record in records {
+            let r = match record {
+                WalRecord::Insert { key, value } => {
+                    Insert { db: self.db }.exec(key, value).map(drop)
+                }
+                WalRecord::Delete { key } => Delete { db: self.db }.exec(&key).map(drop),
+                // Only redo the split if the new main page wasn't written.
+                WalRecord::Split { new_main_page_id } => {
+                    if self.db.n_main_pages() == new_main_page_id {
+                        Split { db: self.db }.exec()
+                    } else {
+                        Ok(())
+                    }
+                }
+                WalRecord::Batch { puts, deletes } => {
+                    let mut batch = WriteBatch::new();
+                    for (k, v) in puts {
+                        batch.put(k, v);
+                    }
+                    for k in deletes {
+                        batch.delete(k);
+                    }
+                    Batch { db: self.db }.replay(batch)
+                }
+            };
+            match r {
+                // The write failed before the crash too.
+                Ok(()) | Err(Error::TooLarge) | Err(Error::InvalidKey) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_torn_tail() {
+        let store = Arc::new(MemIo::new());
+        let (mut wal, records) = Wal::open(Box::new(store.clone())).unwrap();
+        assert!(records.is_empty());
+
+        let records: Vec<WalRecord> = (0..3u8)
+            .map(|i| WalRecord::Insert {
+                key: vec![i],
+                value: vec![i; 10],
+            })
+            .collect();
+        for r in &records {
+            wal.append(r).unwrap();
+        }
+        let len = store.len().unwrap();
+        store.set_len(len - 1).unwrap();
+
+        let (_, read) = Wal::open(Box::new(store.clone())).unwrap();
+        assert_eq!(read, records[..2]);
+
+        // The stale records after a new one are not read.
+        let (mut wal, _) = Wal::open(Box::new(store.clone())).unwrap();
+        let new = WalRecord::Delete { key: vec![0] };
+        wal.append(&new).unwrap();
+        let (_, read) = Wal::open(Box::new(store.clone())).unwrap();
+        assert_eq!(read, [records[0].clone(), records[1].clone(), new.clone()]);
+
+        wal.truncate().unwrap();
+        store.write_at(&[0xff; 8], 0).unwrap();
+        let (_, read) = Wal::open(Box::new(store)).unwrap();
+        assert!(read.is_empty());
+    }
+}
@@ -2,19 +2,55 @@ use super::*;
 
 type ArchivedPage = <Page as rkyv::Archive>::Archived;
 
+/// Estimated bytes an entry takes in the encoded page besides the key and the value.
+/// Two relative pointers for the key and the value plus the control bytes of the hash table.
+pub const KV_OVERHEAD: u64 = 20;
+
+pub fn kv_cost(key: &[u8], value: &[u8]) -> u64 {
+    key.len() as u64 + value.len() as u64 + KV_OVERHEAD
+}
+
 pub fn encode_page(page: &Page) -> Vec<u8> {
     rkyv::to_bytes::<rkyv::rancor::Error>(page)
         .unwrap()
         .to_vec()
 }
 
+/// `max_len` is the maximum length of the encoded page which is given by `Device::max_data_len`.
+pub fn page_fits(page: &Page, max_len: usize) -> bool {
+    encode_page(page).len() <= max_len
+}
+
 pub fn decode_page(buf: &[u8]) -> Result<Page> {
     let page = rkyv::from_bytes::<Page, rkyv::rancor::Error>(buf)?;
     Ok(page)
 }
 
+/// Validate the encoded page so it can be accessed without copying.
+pub fn check_page(buf: &[u8]) -> bool {
+    rkyv::access::<ArchivedPage, rkyv::rancor::Error>(buf).is_ok()
+}
+
+/// The buffer holding the page. Either a copy on the heap or the mapping of the file.
+#[derive(Clone)]
+pub enum PageBuf {
+    Heap(Arc<AlignedVec>),
+    Mapped(Arc<Mapping>),
+}
+
+impl std::ops::Deref for PageBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            PageBuf::Heap(buf) => buf,
+            PageBuf::Mapped(m) => m,
+        }
+    }
+}
+
 pub struct PageRef {
-    pub buf: AlignedVec,
+    pub buf: PageBuf,
     pub data_range: Range<usize>,
 }
 
@@ -34,6 +70,21 @@ impl PageRef {
         page.kv_pairs.get(key).map(|v| v.as_slice())
     }
 
+    pub fn kv_pairs(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.archived()
+            .kv_pairs
+            .iter()
+            .map(|(k, v)| (k.as_slice(), v.as_slice()))
+    }
+
+    /// False if the key is surely not in the chain of this main page.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.archived()
+            .filter
+            .as_ref()
+            .is_none_or(|bits| filter_contains(bits.as_slice(), key))
+    }
+
     pub fn overflow_id(&self) -> Option<u64> {
         self.archived().overflow_id.as_ref().map(|x| x.to_native())
     }
@@ -0,0 +1,304 @@
+use super::*;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+
+/// The number of locks the buckets are striped over.
+const N_LOCKS: u64 = 64;
+
+/// A handle to share a table between threads.
+///
+/// Any number of threads can read while one thread writes. Writers are serialized
+/// and each write locks only the buckets it touches, so reads of other buckets are never blocked.
+///
+/// A split locks both the old and the new bucket while it moves the pairs
+/// and publishes the new split state before releasing them. A reader waiting for the locks
+/// finds the split state changed and looks the key up again in the right bucket.
+/// So a reader sees the table either before or after the split, never a pair half-moved.
+/// The same holds for merges.
+pub struct SharedForeverHash {
+    db: Mutex<ForeverHash>,
+    main_pages: Arc<Device>,
+    overflow_pages: Arc<Device>,
+    hasher: Arc<dyn KeyHasher>,
+    locks: Vec<RwLock<()>>,
+    /// `main_base_level` in the top 8 bits and `next_split_main_page_id` in the rest.
+    split_state: AtomicU64,
+    n_items: AtomicU64,
+    /// The number of writes done. Used by `SyncPolicy::GroupCommit`.
+    n_writes: AtomicU64,
+    group: Mutex<GroupCommit>,
+    group_done: Condvar,
+}
+
+#[derive(Default)]
+struct GroupCommit {
+    /// The writes up to this are persisted.
+    n_synced: u64,
+    /// True while a writer is flushing for the others.
+    syncing: bool,
+}
+
+impl SharedForeverHash {
+    pub fn new(db: ForeverHash) -> Self {
+        let shared = Self {
+            main_pages: db.main_pages.clone(),
+            overflow_pages: db.overflow_pages.clone(),
+            hasher: db.hasher.clone(),
+            locks: (0..N_LOCKS).map(|_| RwLock::new(())).collect(),
+            split_state: AtomicU64::new(0),
+            n_items: AtomicU64::new(0),
+            n_writes: AtomicU64::new(0),
+            group: Mutex::new(GroupCommit::default()),
+            group_done: Condvar::new(),
+            db: Mutex::new(db),
+        };
+        shared.publish(&shared.db.lock().unwrap());
+        shared
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let hash = self.hasher.hash(key).ok_or(Error::InvalidKey)?;
+        loop {
+            let state = self.split_state.load(Ordering::Acquire);
+            let (main_base_level, next_split_main_page_id) = unpack(state);
+            let b = calc_main_page_id(hash, main_base_level, next_split_main_page_id);
+
+            let _lock = self.locks[(b % N_LOCKS) as usize].read().unwrap();
+            // The bucket was split or merged before the lock was taken.
+            if self.split_state.load(Ordering::Acquire) != state {
+                continue;
+            }
+            return op::get_in_chain(self, b, key);
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.n_items.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.modify(key, |_| Change::Put(value))
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.modify(key.to_vec(), |_| Change::Delete)
+    }
+
+    /// See `ForeverHash::insert_if_absent`.
+    pub fn insert_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        let old = self.modify(key, Change::put_if_absent(value))?;
+        Ok(old.is_none())
+    }
+
+    /// See `ForeverHash::compare_and_swap`.
+    pub fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        new: Vec<u8>,
+    ) -> Result<bool> {
+        let mut swapped = false;
+        self.modify(key, Change::swap_if(expected, new, &mut swapped))?;
+        Ok(swapped)
+    }
+
+    /// See `ForeverHash::update`.
+    pub fn update(
+        &self,
+        key: Vec<u8>,
+        f: impl FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.modify(key, Change::put_or_delete(f))
+    }
+
+    /// See `ForeverHash::remove_if`.
+    pub fn remove_if(
+        &self,
+        key: &[u8],
+        pred: impl FnOnce(&[u8]) -> bool,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut removed = false;
+        let old = self.modify(key.to_vec(), Change::delete_if(pred, &mut removed))?;
+        Ok(old.filter(|_| removed))
+    }
+
+    /// The decision is made under the bucket lock so no other writer changes the value in between.
+    fn modify(
+        &self,
+        key: Vec<u8>,
+        decide: impl FnOnce(Option<&[u8]>) -> Change,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut db = self.db.lock().unwrap();
+
+        db.drop_journals_of(&key)?;
+        let b = db.calc_main_page_id(&key)?;
+        let (old, applied) = {
+            let _locks = self.lock_buckets(&[b]);
+            let r = op::Modify { db: &mut db }.exec(key, decide)?;
+            self.publish(&db);
+            r
+        };
+
+        match applied {
+            Applied::Put if db.load_factor() > 0.8 => {
+                let split_id = db.next_split_main_page_id;
+                let new_split_id = split_id + (1 << db.main_base_level);
+                let _locks = self.lock_buckets(&[split_id, new_split_id]);
+                op::Split { db: &mut db }.exec().ok();
+                self.publish(&db);
+            }
+            Applied::Delete if db.load_factor() < 0.3 => {
+                let merge = op::Merge { db: &mut db };
+                let (buddy_id, last_id) = merge.buddy_pair();
+                let _locks = self.lock_buckets(&[buddy_id, last_id]);
+                let r = merge.exec();
+                self.publish(&db);
+                r?;
+            }
+            _ => {}
+        }
+
+        self.sync_after_write(db)?;
+        Ok(old)
+    }
+
+    /// The batch touches many buckets so all the readers wait until it is applied.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let SharedGuard { mut db, _locks } = self.lock();
+        let r = op::Batch { db: &mut db }.exec(batch);
+        self.publish(&db);
+        r
+    }
+
+    /// Persist all the writes so far regardless of the sync policy.
+    pub fn sync(&self) -> Result<()> {
+        self.db.lock().unwrap().sync()
+    }
+
+    /// Lock the table for the operations not available on the handle, like iteration.
+    /// Other writers and the readers of the buckets being written wait until the guard is dropped.
+    pub fn lock(&self) -> SharedGuard<'_> {
+        SharedGuard {
+            db: self.db.lock().unwrap(),
+            _locks: self.locks.iter().map(|l| l.write().unwrap()).collect(),
+        }
+    }
+
+    pub fn into_inner(self) -> ForeverHash {
+        self.db.into_inner().unwrap()
+    }
+
+    pub fn close(self) -> Result<()> {
+        self.into_inner().close()
+    }
+
+    /// Take the write locks in the order of the lock index not to deadlock with another writer.
+    fn lock_buckets(&self, ids: &[u64]) -> Vec<RwLockWriteGuard<'_, ()>> {
+        let mut idx: Vec<u64> = ids.iter().map(|id| id % N_LOCKS).collect();
+        idx.sort_unstable();
+        idx.dedup();
+        idx.into_iter()
+            .map(|i| self.locks[i as usize].write().unwrap())
+            .collect()
+    }
+
+    /// Flush after a write if the sync policy says so.
+    /// With `SyncPolicy::GroupCommit`, the table is unlocked first so the next writers can join the flush.
+    fn sync_after_write(&self, mut db: MutexGuard<'_, ForeverHash>) -> Result<()> {
+        if db.sync_policy != SyncPolicy::GroupCommit {
+            return db.sync_after_write();
+        }
+        let seq = self.n_writes.fetch_add(1, Ordering::AcqRel) + 1;
+        drop(db);
+
+        let mut group = self.group.lock().unwrap();
+        loop {
+            if group.n_synced >= seq {
+                return Ok(());
+            }
+            if group.syncing {
+                group = self.group_done.wait(group).unwrap();
+                continue;
+            }
+
+            // Flush for all the writes done so far.
+            group.syncing = true;
+            let target = self.n_writes.load(Ordering::Acquire);
+            drop(group);
+            let r = self
+                .overflow_pages
+                .sync()
+                .and_then(|_| self.main_pages.sync());
+
+            group = self.group.lock().unwrap();
+            group.syncing = false;
+            if r.is_ok() {
+                group.n_synced = group.n_synced.max(target);
+            }
+            self.group_done.notify_all();
+            r?;
+        }
+    }
+
+    /// Make the state of the writer visible to the readers. Called with the locks of the buckets changed.
+    fn publish(&self, db: &ForeverHash) {
+        let state = pack(db.main_base_level, db.next_split_main_page_id);
+        self.split_state.store(state, Ordering::Release);
+        self.n_items.store(db.n_items, Ordering::Release);
+    }
+}
+
+// Read without the table lock. The caller holds the lock of the bucket.
+impl Pages for SharedForeverHash {
+    fn page_ref(&self, page_id: PageId) -> Result<PageRef> {
+        match page_id {
+            PageId::Main(b) => self.main_pages.expect_page_ref(b),
+            PageId::Overflow(id) => self.overflow_pages.expect_page_ref(id),
+        }
+    }
+
+    fn n_main_pages(&self) -> u64 {
+        let (main_base_level, next_split_main_page_id) =
+            unpack(self.split_state.load(Ordering::Acquire));
+        (1 << main_base_level) + next_split_main_page_id
+    }
+}
+
+/// Exclusive access to the table taken by `SharedForeverHash::lock`.
+pub struct SharedGuard<'a> {
+    db: MutexGuard<'a, ForeverHash>,
+    _locks: Vec<RwLockWriteGuard<'a, ()>>,
+}
+
+impl std::ops::Deref for SharedGuard<'_> {
+    type Target = ForeverHash;
+
+    fn deref(&self) -> &ForeverHash {
+        &self.db
+    }
+}
+
+fn pack(main_base_level: u8, next_split_main_page_id: u64) -> u64 {
+    ((main_base_level as u64) << 56) | next_split_main_page_id
+}
+
+fn unpack(state: u64) -> (u8, u64) {
+    ((state >> 56) as u8, state & ((1 << 56) - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack() {
+        for (level, split_id) in [(1, 0), (7, 100), (40, (1 << 40) - 1)] {
+            assert_eq!(unpack(pack(level, split_id)), (level, split_id));
+        }
+    }
+}
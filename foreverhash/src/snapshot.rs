@@ -0,0 +1,79 @@
+use super::*;
+
+use std::sync::RwLock;
+
+/// The versions of the pages at the time of the snapshot, saved before the pages are overwritten.
+/// `None` if the page didn't exist.
+#[derive(Default)]
+pub struct Preserved {
+    pub pages: RwLock<HashMap<u64, Option<Frame>>>,
+}
+
+/// A read-only view of the table as it was when `ForeverHash::snapshot` was called.
+///
+/// Later writes to the table don't change what the snapshot sees: before a page is overwritten
+/// or truncated for the first time since the snapshot was taken, its current version is saved in the snapshot.
+/// The saved pages are kept in memory until the snapshot is dropped.
+pub struct Snapshot {
+    main_pages: Arc<Device>,
+    overflow_pages: Arc<Device>,
+    hasher: Arc<dyn KeyHasher>,
+    main_base_level: u8,
+    next_split_main_page_id: u64,
+    n_items: u64,
+    preserved_main: Arc<Preserved>,
+    preserved_overflow: Arc<Preserved>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(db: &ForeverHash) -> Self {
+        let preserved_main = Arc::new(Preserved::default());
+        let preserved_overflow = Arc::new(Preserved::default());
+        db.main_pages.add_snapshot(&preserved_main);
+        db.overflow_pages.add_snapshot(&preserved_overflow);
+
+        Self {
+            main_pages: db.main_pages.clone(),
+            overflow_pages: db.overflow_pages.clone(),
+            hasher: db.hasher.clone(),
+            main_base_level: db.main_base_level,
+            next_split_main_page_id: db.next_split_main_page_id,
+            n_items: db.n_items,
+            preserved_main,
+            preserved_overflow,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.n_items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n_items == 0
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let hash = self.hasher.hash(key).ok_or(Error::InvalidKey)?;
+        let b = calc_main_page_id(hash, self.main_base_level, self.next_split_main_page_id);
+        op::get_in_chain(self, b, key)
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(self, Cursor::default())
+    }
+}
+
+impl Pages for Snapshot {
+    fn page_ref(&self, page_id: PageId) -> Result<PageRef> {
+        match page_id {
+            PageId::Main(b) => self.main_pages.snapshot_page_ref(b, &self.preserved_main),
+            PageId::Overflow(id) => self
+                .overflow_pages
+                .snapshot_page_ref(id, &self.preserved_overflow),
+        }
+    }
+
+    fn n_main_pages(&self) -> u64 {
+        (1 << self.main_base_level) + self.next_split_main_page_id
+    }
+}
@@ -0,0 +1,297 @@
+use super::*;
+
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The storage under a page file.
+///
+/// Implement this to put the table on media other than a file, like a raw block device.
+/// Reading beyond the end must fill the buffer with zeros and writing beyond the end extends the store.
+pub trait BlockIo: Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+    /// Persist all the writes so far.
+    fn flush(&self) -> io::Result<()>;
+    fn len(&self) -> io::Result<u64>;
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The file under the store. Only stores on a file can be mapped.
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+// To keep a handle of the store given to the table.
+impl<T: BlockIo + ?Sized> BlockIo for Arc<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        (**self).write_at(buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        (**self).len()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        (**self).as_file()
+    }
+}
+
+pub struct FileIo {
+    f: File,
+}
+
+impl FileIo {
+    pub fn new(f: File) -> Self {
+        Self { f }
+    }
+
+    /// Open or create the file.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let f = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Self::new(f))
+    }
+}
+
+impl BlockIo for FileIo {
+    fn read_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.f.read_at(buf, offset) {
+                Ok(0) => break,
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        buf.fill(0);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.f.write_all_at(buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.f.sync_all()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.f.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.f.set_len(len)
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(&self.f)
+    }
+}
+
+/// Store in memory. Nothing is persisted but the table can be reopened
+/// while a handle of the store is kept.
+#[derive(Default)]
+pub struct MemIo {
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemIo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BlockIo for MemIo {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let data = self.data.lock().unwrap();
+        let start = (offset as usize).min(data.len());
+        let end = (offset as usize + buf.len()).min(data.len());
+        let n = end - start;
+        buf[..n].copy_from_slice(&data[start..end]);
+        buf[n..].fill(0);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let end = offset as usize + buf.len();
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[(offset as usize)..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.lock().unwrap().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.data.lock().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Faults {
+    /// The number of writes to succeed before failing.
+    writes_left: Option<u64>,
+    /// The bytes a failing write puts before failing.
+    torn_len: usize,
+    fail_flush: bool,
+    fail_reads: bool,
+}
+
+/// Wraps a store and fails the operations on demand to test the error paths.
+/// Once a write fails, all the following writes fail until `heal` is called.
+pub struct FaultyIo<S> {
+    inner: S,
+    faults: Mutex<Faults>,
+    n_writes: AtomicU64,
+    n_flushes: AtomicU64,
+}
+
+impl<S: BlockIo> FaultyIo<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            faults: Mutex::new(Faults::default()),
+            n_writes: AtomicU64::new(0),
+            n_flushes: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of writes which succeeded.
+    pub fn n_writes(&self) -> u64 {
+        self.n_writes.load(Ordering::Relaxed)
+    }
+
+    /// The number of flushes which succeeded.
+    pub fn n_flushes(&self) -> u64 {
+        self.n_flushes.load(Ordering::Relaxed)
+    }
+
+    /// Let `n` more writes succeed and fail the rest.
+    pub fn fail_writes_after(&self, n: u64) {
+        self.faults.lock().unwrap().writes_left = Some(n);
+    }
+
+    /// Failing writes put the first `len` bytes before failing.
+    pub fn tear_writes(&self, len: usize) {
+        self.faults.lock().unwrap().torn_len = len;
+    }
+
+    pub fn fail_flush(&self, fail: bool) {
+        self.faults.lock().unwrap().fail_flush = fail;
+    }
+
+    pub fn fail_reads(&self, fail: bool) {
+        self.faults.lock().unwrap().fail_reads = fail;
+    }
+
+    /// Clear all the faults.
+    pub fn heal(&self) {
+        *self.faults.lock().unwrap() = Faults::default();
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+fn injected() -> io::Error {
+    io::Error::other("injected fault")
+}
+
+impl<S: BlockIo> BlockIo for FaultyIo<S> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        if self.faults.lock().unwrap().fail_reads {
+            return Err(injected());
+        }
+        self.inner.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        match &mut faults.writes_left {
+            Some(0) => {
+                let n = faults.torn_len.min(buf.len());
+                self.inner.write_at(&buf[..n], offset)?;
+                return Err(injected());
+            }
+            Some(n) => *n -= 1,
+            None => {}
+        }
+        self.inner.write_at(buf, offset)?;
+        self.n_writes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if self.faults.lock().unwrap().fail_flush {
+            return Err(injected());
+        }
+        self.inner.flush()?;
+        self.n_flushes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faulty_io() {
+        let io = FaultyIo::new(MemIo::new());
+        io.fail_writes_after(1);
+        io.tear_writes(2);
+
+        io.write_at(&[1; 4], 0).unwrap();
+        assert!(io.write_at(&[2; 4], 0).is_err());
+
+        let mut buf = [0xff; 6];
+        io.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [2, 2, 1, 1, 0, 0]);
+
+        io.heal();
+        io.write_at(&[3; 4], 0).unwrap();
+        assert_eq!(io.len().unwrap(), 4);
+    }
+}
@@ -0,0 +1,163 @@
+use super::*;
+
+use op::JournalHead;
+
+/// The version of the on-disk format. Bump this when the layout of the pages changes.
+pub const FORMAT_VERSION: u32 = 6;
+
+// The superblock is double-buffered in the header page of the main page file.
+// The slots are placed in the first 1 KiB so they can be read before knowing the page size.
+// The slot with the bigger `seq` is the current one and the other is overwritten next time,
+// so a torn write of the superblock never loses both.
+const SLOT_SIZE: usize = 512;
+const N_SLOTS: u64 = 2;
+
+/// The longest `KeyHasher::params` the slot has room for with all the other fields set.
+pub const MAX_HASHER_PARAMS_LEN: usize = 256;
+
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Debug, Clone, PartialEq)]
+pub struct Superblock {
+    pub seq: u64,
+    pub version: u32,
+    pub page_size: u32,
+    pub hasher_id: u8,
+    pub hasher_params: Vec<u8>,
+    /// False while the table is open. If the table is found dirty on open,
+    /// the fields below can't be trusted and all the pages are traversed to restore them.
+    pub clean: bool,
+
+    pub main_base_level: u8,
+    pub next_split_main_page_id: u64,
+    pub next_overflow_id: u64,
+    pub n_items: u64,
+    pub n_bytes: u64,
+    /// The head of the free overflow pages chain. Only valid if the table is clean.
+    pub free_overflow_head: Option<u64>,
+    /// The batches to apply again if the table is dirty, oldest first.
+    pub journals: Vec<JournalHead>,
+}
+
+impl Superblock {
+    fn encode(&self) -> Vec<u8> {
+        let data = rkyv::to_bytes::<rkyv::rancor::Error>(self).unwrap();
+        // The length of the params is checked when the table is opened.
+        assert!(8 + data.len() <= SLOT_SIZE);
+
+        let crc = crc32fast::hash(&data);
+        let data_len = data.len() as u32;
+
+        let mut out = Vec::with_capacity(SLOT_SIZE);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out.extend_from_slice(&data_len.to_le_bytes());
+        out.extend_from_slice(&data);
+        out.resize(SLOT_SIZE, 0);
+        out
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let stored_crc = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+        let data_len = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+        if data_len == 0 || 8 + data_len > SLOT_SIZE {
+            return None;
+        }
+
+        let data = &buf[8..(8 + data_len)];
+        if stored_crc != crc32fast::hash(data) {
+            return None;
+        }
+
+        rkyv::from_bytes::<Self, rkyv::rancor::Error>(data).ok()
+    }
+
+    /// Returns the current superblock or `None` if no valid superblock is found.
+    pub(crate) fn read(device: &Device) -> Result<Option<Self>> {
+        let mut buf = vec![0u8; SLOT_SIZE * N_SLOTS as usize];
+        device.read_header(&mut buf, 0)?;
+
+        let sb = buf
+            .chunks(SLOT_SIZE)
+            .filter_map(Self::decode)
+            .max_by_key(|sb| sb.seq);
+
+        Ok(sb)
+    }
+
+    /// Writes the superblock into the slot not holding the current one.
+    pub(crate) fn write(&self, device: &Device) -> Result<()> {
+        let slot = self.seq % N_SLOTS;
+        device.write_header(&self.encode(), slot * SLOT_SIZE as u64)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_latest() {
+        let device = Device::new_main(Box::new(MemIo::new()), 4096);
+
+        assert_eq!(Superblock::read(&device).unwrap(), None);
+
+        let mut sb = Superblock {
+            seq: 1,
+            version: FORMAT_VERSION,
+            page_size: 4096,
+            hasher_id: 0,
+            hasher_params: vec![],
+            clean: false,
+            main_base_level: 1,
+            next_split_main_page_id: 0,
+            next_overflow_id: 0,
+            n_items: 0,
+            n_bytes: 0,
+            free_overflow_head: None,
+            journals: vec![],
+        };
+        sb.write(&device).unwrap();
+
+        sb.seq = 2;
+        sb.clean = true;
+        sb.n_items = 42;
+        sb.write(&device).unwrap();
+        assert_eq!(Superblock::read(&device).unwrap(), Some(sb.clone()));
+
+        // Tear the latest slot. The older one is still valid.
+        device.write_header(&[0xff; 16], 0).unwrap();
+        let old = Superblock::read(&device).unwrap().unwrap();
+        assert_eq!(old.seq, 1);
+        assert!(!old.clean);
+    }
+
+    #[test]
+    fn test_longest_params_fit() {
+        let device = Device::new_main(Box::new(MemIo::new()), 4096);
+
+        let sb = Superblock {
+            seq: u64::MAX,
+            version: FORMAT_VERSION,
+            page_size: 1 << 20,
+            hasher_id: 255,
+            hasher_params: vec![0xff; MAX_HASHER_PARAMS_LEN],
+            clean: true,
+            main_base_level: 63,
+            next_split_main_page_id: u64::MAX,
+            next_overflow_id: u64::MAX,
+            n_items: u64::MAX,
+            n_bytes: u64::MAX,
+            free_overflow_head: Some(u64::MAX),
+            // At most the batch just written and the one before it.
+            journals: vec![
+                JournalHead {
+                    puts: Some(u64::MAX),
+                    deletes: Some(u64::MAX),
+                    crc: u32::MAX,
+                };
+                2
+            ],
+        };
+        sb.write(&device).unwrap();
+        assert_eq!(Superblock::read(&device).unwrap(), Some(sb));
+    }
+}
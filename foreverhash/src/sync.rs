@@ -0,0 +1,37 @@
+use std::time::{Duration, Instant};
+
+/// When the writes are flushed to the disk.
+/// `close` and `sync` always flush regardless of the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Never flush. The writes survive a crash of the process but
+    /// a crash of the OS may leave the table inconsistent.
+    Never,
+    /// Flush only to keep the table consistent on crash: before an overflow page is linked,
+    /// before an updated pair is removed from its old page, and on splits and merges.
+    /// The latest writes may be lost.
+    #[default]
+    OnSplit,
+    /// Flush after every write. A write is durable when it returns.
+    EveryWrite,
+    /// Flush after a write if the interval has passed since the last flush.
+    Interval(Duration),
+    /// Like `EveryWrite`, but the writers of a shared handle waiting at the same time share one flush.
+    GroupCommit,
+}
+
+impl SyncPolicy {
+    /// True if the flushes to keep the table consistent on crash are done.
+    pub fn ordered(&self) -> bool {
+        *self != SyncPolicy::Never
+    }
+
+    /// True if a write should be followed by a flush.
+    pub fn is_due(&self, last_sync: Instant) -> bool {
+        match self {
+            SyncPolicy::Never | SyncPolicy::OnSplit => false,
+            SyncPolicy::EveryWrite | SyncPolicy::GroupCommit => true,
+            SyncPolicy::Interval(d) => last_sync.elapsed() >= *d,
+        }
+    }
+}
@@ -0,0 +1,526 @@
+// Simulate crashes at every write to check that the write ordering keeps the table consistent.
+
+use foreverhash::*;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+const MAIN: usize = 0;
+const OVERFLOW: usize = 1;
+const WAL: usize = 2;
+
+enum Op {
+    Write {
+        dev: usize,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Persists the writes to the file so far. The other files are not.
+    Flush {
+        dev: usize,
+    },
+    SetLen {
+        dev: usize,
+        len: u64,
+    },
+}
+
+/// Disk shared by the main and overflow page files and the redo log.
+/// Every write and flush is recorded in the order they are issued.
+#[derive(Default)]
+struct SimDisk {
+    log: Mutex<Vec<Op>>,
+}
+
+impl SimDisk {
+    fn n_ops(&self) -> usize {
+        self.log.lock().unwrap().len()
+    }
+}
+
+struct SimIo {
+    disk: Arc<SimDisk>,
+    dev: usize,
+    mem: MemIo,
+}
+
+impl SimIo {
+    fn new(disk: Arc<SimDisk>, dev: usize) -> Self {
+        Self {
+            disk,
+            dev,
+            mem: MemIo::new(),
+        }
+    }
+}
+
+impl BlockIo for SimIo {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.mem.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.disk.log.lock().unwrap().push(Op::Write {
+            dev: self.dev,
+            offset,
+            data: buf.to_vec(),
+        });
+        self.mem.write_at(buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.disk
+            .log
+            .lock()
+            .unwrap()
+            .push(Op::Flush { dev: self.dev });
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.mem.len()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.disk
+            .log
+            .lock()
+            .unwrap()
+            .push(Op::SetLen { dev: self.dev, len });
+        self.mem.set_len(len)
+    }
+}
+
+/// The images of the page files after replaying a prefix of the log.
+#[derive(Clone, Default)]
+struct Images([Vec<u8>; 3]);
+
+impl Images {
+    /// Apply the op. If `torn` is given, only the first bytes of the write reach the disk.
+    fn apply(&mut self, op: &Op, torn: Option<usize>) {
+        match op {
+            Op::Write { dev, offset, data } => {
+                let data = &data[..torn.unwrap_or(data.len()).min(data.len())];
+                let img = &mut self.0[*dev];
+                let end = *offset as usize + data.len();
+                if end > img.len() {
+                    img.resize(end, 0);
+                }
+                img[(*offset as usize)..end].copy_from_slice(data);
+            }
+            Op::Flush { .. } => {}
+            Op::SetLen { dev, len } => self.0[*dev].resize(*len as usize, 0),
+        }
+    }
+
+    fn open(&self, options: &Options) -> ForeverHash {
+        let store = |img: &Vec<u8>| {
+            let io = MemIo::new();
+            io.write_at(img, 0).unwrap();
+            Box::new(io)
+        };
+        let r = open(
+            store(&self.0[MAIN]),
+            store(&self.0[OVERFLOW]),
+            store(&self.0[WAL]),
+            options,
+        );
+        r.unwrap_or_else(|e| panic!("{FAILED}: can't open: {e:?}"))
+    }
+}
+
+/// The prefix of the failures found by the checks.
+const FAILED: &str = "crash check failed";
+
+fn open(
+    main: Box<dyn BlockIo>,
+    overflow: Box<dyn BlockIo>,
+    wal: Box<dyn BlockIo>,
+    options: &Options,
+) -> Result<ForeverHash, Error> {
+    let options = options.clone();
+    if options.wal {
+        ForeverHash::open_with_wal_stores(main, overflow, wal, options)
+    } else {
+        ForeverHash::open_with_stores(main, overflow, options)
+    }
+}
+
+fn options() -> Options {
+    // Small pages to have many splits and overflow pages with a few keys.
+    Options {
+        page_size: 1024,
+        ..Default::default()
+    }
+}
+
+#[derive(Clone)]
+enum Cmd {
+    Insert(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    Batch(Vec<Cmd>),
+    Sync,
+}
+
+impl Cmd {
+    fn exec(&self, fh: &mut ForeverHash) {
+        match self {
+            Cmd::Insert(k, v) => {
+                fh.insert(k.clone(), v.clone()).unwrap();
+            }
+            Cmd::Delete(k) => {
+                fh.delete(k).unwrap();
+            }
+            Cmd::Batch(cmds) => {
+                let mut batch = WriteBatch::new();
+                for cmd in cmds {
+                    match cmd {
+                        Cmd::Insert(k, v) => batch.put(k.clone(), v.clone()),
+                        Cmd::Delete(k) => batch.delete(k.clone()),
+                        Cmd::Batch(_) | Cmd::Sync => unreachable!(),
+                    }
+                }
+                fh.write_batch(batch).unwrap();
+            }
+            Cmd::Sync => fh.sync().unwrap(),
+        }
+    }
+
+    /// True if the command is durable once acknowledged, whatever is lost after it.
+    fn durable(&self, options: &Options) -> bool {
+        match self {
+            Cmd::Batch(_) => options.sync_policy.ordered(),
+            Cmd::Sync => true,
+            _ => false,
+        }
+    }
+
+    fn apply(&self, model: &mut HashMap<Vec<u8>, Vec<u8>>) {
+        match self {
+            Cmd::Insert(k, v) => {
+                model.insert(k.clone(), v.clone());
+            }
+            Cmd::Delete(k) => {
+                model.remove(k);
+            }
+            Cmd::Batch(cmds) => {
+                for cmd in cmds {
+                    cmd.apply(model);
+                }
+            }
+            Cmd::Sync => {}
+        }
+    }
+}
+
+/// Check the table recovered from a crash.
+/// The pairs must agree with the acknowledged commands, either before or after the command in flight.
+fn check(fh: &ForeverHash, model: &HashMap<Vec<u8>, Vec<u8>>, in_flight: Option<&Cmd>) {
+    let found = read_all(fh);
+    let keys: HashSet<&Vec<u8>> = model.keys().chain(found.keys()).collect();
+    for k in keys {
+        assert_eq!(
+            get(fh, k).as_ref(),
+            found.get(k),
+            "{FAILED}: get and iter differ"
+        );
+    }
+
+    if found == *model {
+        return;
+    }
+    let mut next = model.clone();
+    if let Some(cmd) = in_flight {
+        cmd.apply(&mut next);
+    }
+    assert!(found == next, "{FAILED}: acknowledged pairs are lost");
+}
+
+/// The pairs found by iterating the table. Each key must appear once.
+fn read_all(fh: &ForeverHash) -> HashMap<Vec<u8>, Vec<u8>> {
+    let mut found = HashMap::new();
+    for kv in fh.iter() {
+        let (k, v) = kv.unwrap_or_else(|e| panic!("{FAILED}: can't iterate: {e:?}"));
+        assert!(
+            !found.contains_key(&k),
+            "{FAILED}: key appears twice: {k:?}"
+        );
+        found.insert(k, v);
+    }
+    assert_eq!(fh.len(), found.len() as u64, "{FAILED}: wrong len");
+    found
+}
+
+fn get(fh: &ForeverHash, key: &[u8]) -> Option<Vec<u8>> {
+    fh.get(key)
+        .unwrap_or_else(|e| panic!("{FAILED}: can't get: {e:?}"))
+}
+
+/// Check the table recovered from a crash which lost or tore some of the writes not flushed yet.
+/// Each pair must be one the key had since the oldest of those writes, in `history` or after the command in flight.
+fn check_lost(fh: &ForeverHash, history: &[HashMap<Vec<u8>, Vec<u8>>], in_flight: Option<&Cmd>) {
+    let found = read_all(fh);
+
+    let mut states = history.to_vec();
+    if let Some(cmd) = in_flight {
+        let mut next = states.last().unwrap().clone();
+        cmd.apply(&mut next);
+        states.push(next);
+    }
+    let keys: HashSet<&Vec<u8>> = states
+        .iter()
+        .flat_map(|s| s.keys())
+        .chain(found.keys())
+        .collect();
+    for k in keys {
+        let v = get(fh, k);
+        assert_eq!(v.as_ref(), found.get(k), "{FAILED}: get and iter differ");
+        assert!(
+            states.iter().any(|s| s.get(k) == v.as_ref()),
+            "{FAILED}: unexpected value of {k:?}: {v:?}"
+        );
+    }
+}
+
+/// The writes to a file not followed by its flush may reach the disk in any order, torn or not at all.
+/// The number of random outcomes of them tried at each crash point.
+const N_LOST_SAMPLES: usize = 3;
+
+/// xorshift64 to pick the writes lost, seeded for reproducible runs.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+/// Run the commands and crash at every op recorded, with and without tearing the next write,
+/// and with some of the writes since the last flush of their file lost or torn.
+fn run_crash_test(cmds: &[Cmd], options: Options) {
+    let disk = Arc::new(SimDisk::default());
+    let main = SimIo::new(disk.clone(), MAIN);
+    let overflow = SimIo::new(disk.clone(), OVERFLOW);
+    let wal = SimIo::new(disk.clone(), WAL);
+    let mut fh = open(Box::new(main), Box::new(overflow), Box::new(wal), &options).unwrap();
+
+    // The number of ops recorded when each command is acknowledged.
+    let start = disk.n_ops();
+    let mut acked_at = Vec::new();
+    for cmd in cmds {
+        cmd.exec(&mut fh);
+        acked_at.push(disk.n_ops());
+    }
+    // Leave the table dirty.
+    std::mem::forget(fh);
+
+    let log = std::mem::take(&mut *disk.log.lock().unwrap());
+
+    let mut images = Images::default();
+    for op in &log[..start] {
+        images.apply(op, None);
+    }
+    // The images of each file as of its last flush and the ops issued to it since, with their index in the log.
+    let mut durable = images.clone();
+    let mut pending: [Vec<(usize, &Op)>; 3] = Default::default();
+    // The acknowledged states and the index in the log when they were acknowledged.
+    let mut acked = vec![(start, HashMap::new())];
+    // The last acknowledged state which must survive any crash.
+    let mut durable_from = 0;
+    let mut rng = Rng(0x9e3779b97f4a7c15);
+
+    let mut model = HashMap::new();
+    let mut n_acked = 0;
+    for i in start..=log.len() {
+        while n_acked < cmds.len() && acked_at[n_acked] <= i {
+            cmds[n_acked].apply(&mut model);
+            acked.push((acked_at[n_acked], model.clone()));
+            if cmds[n_acked].durable(&options) {
+                durable_from = acked.len() - 1;
+            }
+            n_acked += 1;
+        }
+        let in_flight = cmds.get(n_acked);
+
+        check(&images.open(&options), &model, in_flight);
+
+        // The states since the last one acknowledged before the oldest write which may be lost,
+        // but not before a durable command.
+        // A torn write means the OS crashed, so the writes not flushed yet may be lost with it.
+        let oldest = pending
+            .iter()
+            .filter_map(|ops| ops.first())
+            .map(|(j, _)| *j)
+            .min();
+        let from = acked
+            .iter()
+            .rposition(|(j, _)| *j <= oldest.unwrap_or(i))
+            .unwrap()
+            .max(durable_from);
+        let history: Vec<_> = acked[from..].iter().map(|(_, s)| s.clone()).collect();
+
+        if oldest.is_some() {
+            for _ in 0..N_LOST_SAMPLES {
+                let mut lost = durable.clone();
+                for (_, op) in pending.iter().flatten() {
+                    match op {
+                        Op::Write { data, .. } => match rng.next(3) {
+                            0 => lost.apply(op, None),
+                            1 => lost.apply(op, Some(data.len() / 2)),
+                            _ => {}
+                        },
+                        _ => lost.apply(op, None),
+                    }
+                }
+                check_lost(&lost.open(&options), &history, in_flight);
+            }
+        }
+
+        if let Some(op) = log.get(i) {
+            if let Op::Write { data, .. } = op {
+                let mut torn = images.clone();
+                torn.apply(op, Some(data.len() / 2));
+                check_lost(&torn.open(&options), &history, in_flight);
+            }
+            images.apply(op, None);
+
+            match op {
+                Op::Flush { dev } => {
+                    durable.0[*dev] = images.0[*dev].clone();
+                    pending[*dev].clear();
+                }
+                Op::Write { dev, .. } | Op::SetLen { dev, .. } => pending[*dev].push((i, op)),
+            }
+        }
+    }
+}
+
+fn key(i: u64) -> Vec<u8> {
+    i.to_le_bytes().to_vec()
+}
+
+fn value(i: u64) -> Vec<u8> {
+    vec![i as u8; 8 + (i as usize * 37) % 200]
+}
+
+#[test]
+fn test_crash_insert() {
+    let cmds: Vec<Cmd> = (0..200).map(|i| Cmd::Insert(key(i), value(i))).collect();
+    run_crash_test(&cmds, options());
+}
+
+#[test]
+fn test_crash_update_and_delete() {
+    let mut cmds: Vec<Cmd> = (0..150).map(|i| Cmd::Insert(key(i), value(i))).collect();
+    // Updates move pairs between the pages in the chain.
+    cmds.extend(
+        (0..150)
+            .step_by(3)
+            .map(|i| Cmd::Insert(key(i), value(i + 1))),
+    );
+    // Mass deletes to merge the buckets.
+    cmds.extend((0..150).filter(|i| i % 5 != 0).map(|i| Cmd::Delete(key(i))));
+    run_crash_test(&cmds, options());
+}
+
+fn batch_cmds() -> Vec<Cmd> {
+    let mut cmds: Vec<Cmd> = (0..20)
+        .map(|b| {
+            Cmd::Batch(
+                (b * 10..b * 10 + 10)
+                    .map(|i| Cmd::Insert(key(i), value(i)))
+                    .collect(),
+            )
+        })
+        .collect();
+    // Mixed with single writes.
+    cmds.extend((200..220).map(|i| Cmd::Insert(key(i), value(i))));
+    cmds.push(Cmd::Batch(
+        (0..200)
+            .map(|i| match i % 3 {
+                0 => Cmd::Insert(key(i), value(i + 1)),
+                _ => Cmd::Delete(key(i)),
+            })
+            .collect(),
+    ));
+    cmds.push(Cmd::Delete(key(201)));
+    // Single writes to the keys of the batch before its pages are flushed.
+    cmds.extend((0..30).map(|i| Cmd::Insert(key(i), value(i + 2))));
+    cmds.push(Cmd::Delete(key(3)));
+    // A batch over the keys written before it, then more writes to them until a sync drops its journal.
+    cmds.push(Cmd::Batch(
+        (0..30).map(|i| Cmd::Insert(key(i), value(i + 3))).collect(),
+    ));
+    cmds.extend(
+        (0..30)
+            .step_by(2)
+            .map(|i| Cmd::Insert(key(i), value(i + 4))),
+    );
+    cmds.push(Cmd::Sync);
+    cmds.extend((1..30).step_by(2).map(|i| Cmd::Delete(key(i))));
+    cmds
+}
+
+#[test]
+fn test_crash_batch() {
+    run_crash_test(&batch_cmds(), options());
+}
+
+#[test]
+fn test_crash_batch_wal() {
+    // The batches are logged with the other writes and applied again in order.
+    let options = Options {
+        wal: true,
+        ..options()
+    };
+    run_crash_test(&batch_cmds(), options);
+}
+
+#[test]
+fn test_crash_wal() {
+    let mut cmds: Vec<Cmd> = (0..150).map(|i| Cmd::Insert(key(i), value(i))).collect();
+    cmds.extend(
+        (0..150)
+            .step_by(3)
+            .map(|i| Cmd::Insert(key(i), value(i + 1))),
+    );
+    cmds.extend((0..150).filter(|i| i % 5 != 0).map(|i| Cmd::Delete(key(i))));
+    let options = Options {
+        wal: true,
+        ..options()
+    };
+    run_crash_test(&cmds, options);
+}
+
+#[test]
+fn test_crash_filter() {
+    let mut cmds: Vec<Cmd> = (0..150).map(|i| Cmd::Insert(key(i), value(i))).collect();
+    cmds.extend(
+        (0..150)
+            .step_by(3)
+            .map(|i| Cmd::Insert(key(i), value(i + 1))),
+    );
+    cmds.extend((0..150).filter(|i| i % 5 != 0).map(|i| Cmd::Delete(key(i))));
+    // The filter of each chain must have its keys at any point of a crash.
+    let options = Options {
+        filter_len: 64,
+        ..options()
+    };
+    run_crash_test(&cmds, options);
+}
+
+#[test]
+#[should_panic(expected = "crash check failed")]
+fn test_crash_without_barriers() {
+    // The harness must catch the writes reordered when the flushes are skipped.
+    let mut cmds: Vec<Cmd> = (0..150).map(|i| Cmd::Insert(key(i), value(i))).collect();
+    cmds.extend((0..150).filter(|i| i % 5 != 0).map(|i| Cmd::Delete(key(i))));
+    let options = Options {
+        sync_policy: SyncPolicy::Never,
+        ..options()
+    };
+    run_crash_test(&cmds, options);
+}
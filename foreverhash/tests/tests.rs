@@ -1,4 +1,8 @@
+#![allow(clippy::unnecessary_cast)]
+
 use foreverhash::*;
+use std::sync::Arc;
+use std::time::Duration;
 
 fn vec(i: u64) -> Vec<u8> {
     i.to_le_bytes().to_vec()
@@ -98,3 +102,1528 @@ fn test_restore() {
         assert_eq!(v, vec(i));
     }
 }
+
+#[test]
+fn test_variable_size() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let mut fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+
+    let n = 3000;
+    let range = 0..n;
+
+    let value = |i: u64, round: u64| vec![i as u8; ((i * 7 + round * 13) % 300) as usize];
+
+    for round in 0..2 {
+        for i in range.clone() {
+            fh.insert(vec(i), value(i, round)).unwrap();
+        }
+    }
+
+    assert_eq!(fh.len(), n);
+
+    for i in range.clone() {
+        let v = fh.get(&vec(i)).unwrap().unwrap();
+        assert_eq!(v, value(i, 1));
+    }
+
+    let fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+    assert_eq!(fh.len(), n);
+
+    let big = vec![0; 4096];
+    let mut fh = fh;
+    assert!(matches!(fh.insert(vec(n), big), Err(Error::TooLarge)));
+}
+
+#[test]
+fn test_close_and_open() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let mut fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+
+    let n = 10000;
+    let range = 0..n;
+
+    for i in range.clone() {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    fh.close().unwrap();
+
+    // Opened from the superblock.
+    let mut fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+    assert_eq!(fh.len(), n);
+    for i in range.clone() {
+        fh.insert(vec(i + n), vec(i)).unwrap();
+    }
+    // Crash without closing.
+    std::mem::forget(fh);
+
+    let fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+    assert_eq!(fh.len(), 2 * n);
+    for i in range {
+        assert_eq!(fh.get(&vec(i)).unwrap().unwrap(), vec(i));
+        assert_eq!(fh.get(&vec(i + n)).unwrap().unwrap(), vec(i));
+    }
+}
+
+#[test]
+fn test_reuse_overflow_pages() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let mut fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+
+    let n = 5000;
+    let value = |i: u64| vec![i as u8; (i % 200) as usize];
+
+    // The number of overflow pages.
+    let mut overflow_len = 0;
+    for round in 0..5 {
+        for i in 0..n {
+            fh.insert(vec(i), value(i)).unwrap();
+        }
+        for i in 0..n {
+            assert_eq!(fh.delete(&vec(i)).unwrap(), Some(value(i)));
+        }
+        assert!(fh.is_empty());
+
+        let len = overflow.as_file().metadata().unwrap().len().div_ceil(4096);
+        if round == 0 {
+            overflow_len = len;
+        } else {
+            assert!(len <= overflow_len);
+        }
+
+        if round == 2 {
+            fh.close().unwrap();
+            fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_merge() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let mut fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+
+    let n = 10000;
+    let range = 0..n;
+
+    for i in range.clone() {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    let main_len = main.as_file().metadata().unwrap().len();
+
+    // Delete all but 100 pairs.
+    for i in 100..n {
+        fh.delete(&vec(i)).unwrap();
+    }
+    assert_eq!(fh.len(), 100);
+    assert!(main.as_file().metadata().unwrap().len() < main_len / 10);
+
+    for i in range.clone() {
+        let v = fh.get(&vec(i)).unwrap();
+        if i < 100 {
+            assert_eq!(v, Some(vec(i)));
+        } else {
+            assert_eq!(v, None);
+        }
+    }
+
+    std::mem::forget(fh);
+    let mut fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+    assert_eq!(fh.len(), 100);
+
+    // Grow again.
+    for i in range.clone() {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    for i in range {
+        assert_eq!(fh.get(&vec(i)).unwrap(), Some(vec(i)));
+    }
+}
+
+#[test]
+fn test_merge_error() {
+    let main = Arc::new(FaultyIo::new(MemIo::new()));
+    let overflow = Arc::new(MemIo::new());
+    let mut fh = ForeverHash::open_with_stores(
+        Box::new(main.clone()),
+        Box::new(overflow.clone()),
+        Options::default(),
+    )
+    .unwrap();
+
+    let n = 10000;
+    for i in 0..n {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+
+    // Only the merges flush the main page file.
+    main.fail_flush(true);
+    let mut failed = None;
+    for i in 0..n {
+        if let Err(e) = fh.delete(&vec(i)) {
+            assert!(matches!(e, Error::IO(_)));
+            failed = Some(i);
+            break;
+        }
+    }
+    let failed = failed.unwrap();
+    main.heal();
+
+    // The delete was applied before the merge failed.
+    assert_eq!(fh.len(), n - failed - 1);
+    for i in 0..n {
+        assert_eq!(fh.get(&vec(i)).unwrap().is_some(), i > failed);
+    }
+    for i in failed + 1..n {
+        fh.delete(&vec(i)).unwrap();
+    }
+    assert!(fh.is_empty());
+}
+
+#[test]
+fn test_iter() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let mut fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+
+    let n = 10000;
+    let range = 0..n;
+
+    for i in range.clone() {
+        fh.insert(vec(i), vec(i + 1)).unwrap();
+    }
+
+    let mut pairs: Vec<_> = fh.iter().map(|r| r.unwrap()).collect();
+    pairs.sort();
+    let mut expected: Vec<_> = range.map(|i| (vec(i), vec(i + 1))).collect();
+    expected.sort();
+    assert_eq!(pairs, expected);
+
+    assert_eq!(fh.keys().count(), n as usize);
+    assert_eq!(fh.values().count(), n as usize);
+
+    // Iterate in chunks.
+    let mut pairs = vec![];
+    let mut cursor = Cursor::default();
+    loop {
+        let mut iter = fh.iter_from(Cursor::from_bytes(&cursor.to_bytes()));
+        let chunk: Vec<_> = iter.by_ref().take(777).map(|r| r.unwrap()).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        pairs.extend(chunk);
+        cursor = iter.cursor();
+    }
+    pairs.sort();
+    assert_eq!(pairs, expected);
+}
+
+#[test]
+fn test_page_size() {
+    for page_size in [1024, 16384] {
+        let main = tempfile::NamedTempFile::new().unwrap();
+        let overflow = tempfile::NamedTempFile::new().unwrap();
+        let options = Options {
+            page_size,
+            ..Default::default()
+        };
+        let mut fh = ForeverHash::open_with(main.path(), overflow.path(), options.clone()).unwrap();
+
+        let n = 5000;
+        let range = 0..n;
+
+        for i in range.clone() {
+            fh.insert(vec(i), vec(i)).unwrap();
+        }
+        fh.close().unwrap();
+
+        let r = ForeverHash::open(main.path(), overflow.path());
+        assert!(matches!(r, Err(Error::PageSizeMismatch(n)) if n == page_size));
+
+        let fh = ForeverHash::open_with(main.path(), overflow.path(), options).unwrap();
+        assert_eq!(fh.len(), n);
+        for i in range {
+            assert_eq!(fh.get(&vec(i)).unwrap().unwrap(), vec(i));
+        }
+    }
+
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let options = Options {
+        page_size: 1000,
+        ..Default::default()
+    };
+    let r = ForeverHash::open_with(main.path(), overflow.path(), options);
+    assert!(matches!(r, Err(Error::InvalidPageSize(1000))));
+}
+
+#[test]
+fn test_hasher() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let options = Options {
+        hasher: Arc::new(SipHash13 { k0: 1, k1: 2 }),
+        ..Default::default()
+    };
+    let mut fh = ForeverHash::open_with(main.path(), overflow.path(), options.clone()).unwrap();
+
+    let n = 5000;
+    let range = 0..n;
+
+    for i in range.clone() {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    fh.close().unwrap();
+
+    for hasher in [
+        Arc::new(SipHash13 { k0: 1, k1: 3 }) as Arc<dyn KeyHasher>,
+        Arc::new(Xxh3),
+        Arc::new(IdentityU64),
+    ] {
+        let options = Options {
+            hasher,
+            ..Default::default()
+        };
+        let r = ForeverHash::open_with(main.path(), overflow.path(), options);
+        assert!(matches!(r, Err(Error::HashMismatch(2))));
+    }
+
+    let fh = ForeverHash::open_with(main.path(), overflow.path(), options).unwrap();
+    assert_eq!(fh.len(), n);
+    for i in range {
+        assert_eq!(fh.get(&vec(i)).unwrap().unwrap(), vec(i));
+    }
+}
+
+#[derive(Debug)]
+struct LongParamsHasher(usize);
+
+impl KeyHasher for LongParamsHasher {
+    fn id(&self) -> u8 {
+        129
+    }
+
+    fn params(&self) -> Vec<u8> {
+        vec![1; self.0]
+    }
+
+    fn hash(&self, key: &[u8]) -> Option<u64> {
+        Xxh3.hash(key)
+    }
+}
+
+#[test]
+fn test_hasher_params_too_long() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let options = Options {
+        hasher: Arc::new(LongParamsHasher(1000)),
+        ..Default::default()
+    };
+    let r = ForeverHash::open_with(main.path(), overflow.path(), options);
+    assert!(matches!(r, Err(Error::HasherParamsTooLong(1000))));
+    // Nothing is written to the table.
+    assert_eq!(std::fs::metadata(main.path()).unwrap().len(), 0);
+
+    let options = Options {
+        hasher: Arc::new(LongParamsHasher(256)),
+        ..Default::default()
+    };
+    let mut fh = ForeverHash::open_with(main.path(), overflow.path(), options.clone()).unwrap();
+    fh.insert(vec(1), vec(1)).unwrap();
+    fh.close().unwrap();
+    let fh = ForeverHash::open_with(main.path(), overflow.path(), options).unwrap();
+    assert_eq!(fh.get(&vec(1)).unwrap(), Some(vec(1)));
+}
+
+#[test]
+fn test_short_keys() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let mut fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+
+    let n = 5000u64;
+    let key = |i: u64| i.to_le_bytes()[..(i % 9) as usize].to_vec();
+
+    // Keys are shorter than 8 bytes and some of them collide.
+    let mut expected = std::collections::HashMap::new();
+    for i in 0..n {
+        fh.insert(key(i), vec(i)).unwrap();
+        expected.insert(key(i), vec(i));
+    }
+    assert_eq!(fh.len(), expected.len() as u64);
+
+    for (k, v) in &expected {
+        assert_eq!(fh.get(k).unwrap().as_ref(), Some(v));
+    }
+}
+
+#[derive(Debug)]
+struct FixedSizeHasher;
+
+impl KeyHasher for FixedSizeHasher {
+    fn id(&self) -> u8 {
+        128
+    }
+
+    fn hash(&self, key: &[u8]) -> Option<u64> {
+        let a: [u8; 8] = key.try_into().ok()?;
+        Some(u64::from_le_bytes(a))
+    }
+}
+
+#[test]
+fn test_invalid_key() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let options = Options {
+        hasher: Arc::new(FixedSizeHasher),
+        ..Default::default()
+    };
+    let mut fh = ForeverHash::open_with(main.path(), overflow.path(), options).unwrap();
+
+    fh.insert(vec(1), vec(1)).unwrap();
+    assert!(matches!(
+        fh.insert(vec![1; 4], vec(1)),
+        Err(Error::InvalidKey)
+    ));
+    assert!(matches!(fh.get(&[1; 4]), Err(Error::InvalidKey)));
+    assert!(matches!(
+        fh.get_many(&[vec(1), vec![1; 4]]),
+        Err(Error::InvalidKey)
+    ));
+    assert!(matches!(fh.delete(&[1; 9]), Err(Error::InvalidKey)));
+    assert_eq!(fh.len(), 1);
+}
+
+#[test]
+fn test_cache() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let options = Options {
+        cache_size: 16 * 4096,
+        ..Default::default()
+    };
+    let mut fh = ForeverHash::open_with(main.path(), overflow.path(), options.clone()).unwrap();
+
+    let n = 5000;
+    for i in 0..n {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    for i in 0..n / 2 {
+        fh.delete(&vec(i)).unwrap();
+    }
+    for i in 0..n {
+        let expected = if i < n / 2 { None } else { Some(vec(i)) };
+        assert_eq!(fh.get(&vec(i)).unwrap(), expected);
+    }
+
+    let stats = fh.cache_stats();
+    assert!(stats.hits > 0);
+    assert!(stats.evictions > 0);
+    assert!(stats.used_bytes <= 16 * 4096);
+    fh.close().unwrap();
+
+    // The cache is only in memory.
+    let fh = ForeverHash::open_with(main.path(), overflow.path(), options).unwrap();
+    assert_eq!(fh.len(), n / 2);
+    for i in n / 2..n {
+        assert_eq!(fh.get(&vec(i)).unwrap().unwrap(), vec(i));
+    }
+
+    let fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+    assert_eq!(fh.cache_stats(), CacheStats::default());
+}
+
+#[test]
+fn test_mmap() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let options = Options {
+        mmap: true,
+        ..Default::default()
+    };
+    let mut fh = ForeverHash::open_with(main.path(), overflow.path(), options.clone()).unwrap();
+
+    let n = 10000;
+    for i in 0..n {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    // Shrinks the main page file.
+    for i in 0..n / 2 {
+        fh.delete(&vec(i)).unwrap();
+    }
+    for i in 0..n {
+        let expected = if i < n / 2 { None } else { Some(vec(i)) };
+        assert_eq!(fh.get(&vec(i)).unwrap(), expected);
+    }
+    fh.close().unwrap();
+
+    let fh = ForeverHash::open_with(main.path(), overflow.path(), options).unwrap();
+    assert_eq!(fh.len(), n / 2);
+    assert_eq!(fh.iter().count() as u64, n / 2);
+    for i in n / 2..n {
+        assert_eq!(fh.get(&vec(i)).unwrap().unwrap(), vec(i));
+    }
+}
+
+#[test]
+fn test_stores() {
+    let main = Arc::new(FaultyIo::new(MemIo::new()));
+    let overflow = Arc::new(MemIo::new());
+    let open = || {
+        ForeverHash::open_with_stores(
+            Box::new(main.clone()),
+            Box::new(overflow.clone()),
+            Options::default(),
+        )
+    };
+
+    let mut fh = open().unwrap();
+    let n = 1000;
+    for i in 0..n {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+
+    // Crash in the middle of writing a main page.
+    main.fail_writes_after(0);
+    main.tear_writes(100);
+    assert!(matches!(fh.insert(vec(n), vec(n)), Err(Error::IO(_))));
+    drop(fh);
+    main.heal();
+
+    let fh = open().unwrap();
+    assert!(fh.len() >= n);
+    for i in 0..n {
+        assert_eq!(fh.get(&vec(i)).unwrap().unwrap(), vec(i));
+    }
+    drop(fh);
+
+    // Only the stores on files can be mapped.
+    let options = Options {
+        mmap: true,
+        ..Default::default()
+    };
+    let r = ForeverHash::open_with_stores(Box::new(MemIo::new()), Box::new(MemIo::new()), options);
+    assert!(matches!(r, Err(Error::IO(_))));
+}
+
+#[test]
+fn test_corruption() {
+    let main = Arc::new(MemIo::new());
+    let overflow = Arc::new(MemIo::new());
+    let options = Options {
+        checked_reads: true,
+        ..Default::default()
+    };
+    let open = || {
+        ForeverHash::open_with_stores(
+            Box::new(main.clone()),
+            Box::new(overflow.clone()),
+            options.clone(),
+        )
+    };
+
+    let mut fh = open().unwrap();
+    let n = 1000;
+    for i in 0..n {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    fh.close().unwrap();
+
+    // Break both slots of main page 0, which follow the header page.
+    main.write_at(&[0xff; 32], 4096).unwrap();
+    main.write_at(&[0xff; 32], 2 * 4096).unwrap();
+
+    let fh = open().unwrap();
+    let mut n_corrupted = 0;
+    for i in 0..n {
+        match fh.get(&vec(i)) {
+            Ok(v) => assert_eq!(v.unwrap(), vec(i)),
+            Err(Error::Corruption {
+                file: PageFile::Main,
+                page_id: 0,
+                kind: CorruptionKind::Checksum,
+            }) => n_corrupted += 1,
+            Err(e) => panic!("{e}"),
+        }
+    }
+    assert!(n_corrupted > 0);
+    assert!(fh.iter().any(|kv| kv.is_err_and(|e| e.is_corruption())));
+}
+
+#[test]
+fn test_restore_corruption() {
+    let main = Arc::new(MemIo::new());
+    let overflow = Arc::new(MemIo::new());
+    let open = || {
+        ForeverHash::open_with_stores(
+            Box::new(main.clone()),
+            Box::new(overflow.clone()),
+            Options::default(),
+        )
+    };
+
+    let mut fh = open().unwrap();
+    for i in 0..10000 {
+        fh.insert(vec(i), vec![0; 100]).unwrap();
+    }
+    // Leave the table dirty so it is restored on open.
+    std::mem::forget(fh);
+    let mut main_image = vec![0; main.len().unwrap() as usize];
+    main.read_at(&mut main_image, 0).unwrap();
+
+    // A broken main page in the middle isn't taken as the end of the table.
+    let page = 4096 + 10 * 2 * 4096;
+    main.write_at(&[0xff; 32], page).unwrap();
+    main.write_at(&[0xff; 32], page + 4096).unwrap();
+    assert!(matches!(
+        open(),
+        Err(Error::Corruption {
+            file: PageFile::Main,
+            page_id: 10,
+            kind: CorruptionKind::Checksum,
+        })
+    ));
+
+    // An overflow id beyond the overflow page file.
+    main.write_at(&main_image, 0).unwrap();
+    let len = overflow.len().unwrap();
+    overflow.set_len(len / 2).unwrap();
+    assert!(open().is_err_and(|e| e.is_corruption()));
+}
+
+#[test]
+fn test_no_superblock() {
+    let main = Arc::new(MemIo::new());
+    let overflow = Arc::new(MemIo::new());
+    let open = || {
+        ForeverHash::open_with_stores(
+            Box::new(main.clone()),
+            Box::new(overflow.clone()),
+            Options::default(),
+        )
+    };
+
+    let mut fh = open().unwrap();
+    for i in 0..1000 {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    fh.close().unwrap();
+
+    // Like a file of another format, the pages aren't overwritten.
+    main.write_at(&[0; 1024], 0).unwrap();
+    let len = main.len().unwrap();
+    assert!(matches!(open(), Err(Error::NoSuperblock)));
+    assert_eq!(main.len().unwrap(), len);
+}
+
+#[test]
+fn test_torn_first_superblock() {
+    let main = Arc::new(FaultyIo::new(MemIo::new()));
+    let overflow = Arc::new(MemIo::new());
+    let open = || {
+        ForeverHash::open_with_stores(
+            Box::new(main.clone()),
+            Box::new(overflow.clone()),
+            Options::default(),
+        )
+    };
+
+    // Crash while the superblock of a new table is written.
+    main.fail_writes_after(0);
+    main.tear_writes(20);
+    assert!(matches!(open(), Err(Error::IO(_))));
+    main.heal();
+    assert!(!main.is_empty().unwrap());
+
+    // No page was written, so it is still a new table.
+    let mut fh = open().unwrap();
+    assert!(fh.is_empty());
+    fh.insert(vec(1), vec(1)).unwrap();
+    fh.close().unwrap();
+    let fh = open().unwrap();
+    assert_eq!(fh.get(&vec(1)).unwrap().unwrap(), vec(1));
+}
+
+#[test]
+fn test_verify() {
+    let main = Arc::new(MemIo::new());
+    let overflow = Arc::new(MemIo::new());
+    let open = || {
+        ForeverHash::open_with_stores(
+            Box::new(main.clone()),
+            Box::new(overflow.clone()),
+            Options::default(),
+        )
+    };
+    let verify = || {
+        ForeverHash::verify_stores(
+            Box::new(main.clone()),
+            Box::new(overflow.clone()),
+            Options::default(),
+        )
+        .unwrap()
+    };
+
+    let mut fh = open().unwrap();
+    let n = 5000;
+    for i in 0..n {
+        fh.insert(vec(i), vec![i as u8; 100]).unwrap();
+    }
+    for i in 0..n / 2 {
+        fh.delete(&vec(i)).unwrap();
+    }
+    fh.close().unwrap();
+
+    let report = verify();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert!(report.clean);
+    assert_eq!(report.n_items, n / 2);
+    assert_eq!(
+        report.n_used_overflow_pages + report.n_free_overflow_pages,
+        report.n_overflow_pages
+    );
+
+    // Dirty tables are also verified.
+    let fh = open().unwrap();
+    std::mem::forget(fh);
+    let report = verify();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert!(!report.clean);
+    assert_eq!(report.n_items, n / 2);
+
+    // Break main page 1.
+    main.write_at(&[0xff; 32], 3 * 4096).unwrap();
+    main.write_at(&[0xff; 32], 4 * 4096).unwrap();
+    let report = verify();
+    assert!(report.problems.contains(&Problem::CorruptedPage {
+        file: PageFile::Main,
+        page_id: 1,
+        kind: CorruptionKind::Checksum,
+    }));
+    assert!(report.n_items < n / 2);
+}
+
+#[test]
+fn test_repair() {
+    let main = Arc::new(MemIo::new());
+    let overflow = Arc::new(MemIo::new());
+    // Random buckets to have overflow pages.
+    let options = Options {
+        hasher: Arc::new(Xxh3),
+        ..Default::default()
+    };
+
+    let mut fh = ForeverHash::open_with_stores(
+        Box::new(main.clone()),
+        Box::new(overflow.clone()),
+        options.clone(),
+    )
+    .unwrap();
+    let n = 5000;
+    for i in 0..n {
+        fh.insert(vec(i), vec![0; 100]).unwrap();
+    }
+    for i in 0..n {
+        fh.insert(vec(i), vec![1; 100]).unwrap();
+    }
+    fh.close().unwrap();
+
+    // Break the main pages not split yet, which have long chains.
+    // The overflow pages in their chains become orphaned.
+    let broken = 80..96;
+    for i in broken.clone() {
+        main.write_at(&[0xff; 32], (1 + 2 * i) * 4096).unwrap();
+        main.write_at(&[0xff; 32], (2 + 2 * i) * 4096).unwrap();
+    }
+
+    let dst_main = Arc::new(MemIo::new());
+    let dst_overflow = Arc::new(MemIo::new());
+    let report = ForeverHash::repair_stores(
+        Box::new(main.clone()),
+        Box::new(overflow.clone()),
+        Box::new(dst_main.clone()),
+        Box::new(dst_overflow.clone()),
+        options.clone(),
+    )
+    .unwrap();
+    let lost: Vec<Problem> = broken
+        .map(|page_id| Problem::CorruptedPage {
+            file: PageFile::Main,
+            page_id,
+            kind: CorruptionKind::Checksum,
+        })
+        .collect();
+    assert_eq!(report.lost, lost);
+    assert!(report.n_orphaned_items > 0);
+    assert!(report.n_items < n);
+
+    let report = ForeverHash::verify_stores(
+        Box::new(dst_main.clone()),
+        Box::new(dst_overflow.clone()),
+        options.clone(),
+    )
+    .unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+
+    let fh =
+        ForeverHash::open_with_stores(Box::new(dst_main), Box::new(dst_overflow), options.clone())
+            .unwrap();
+    let mut n_found = 0;
+    for i in 0..n {
+        if let Some(v) = fh.get(&vec(i)).unwrap() {
+            assert_eq!(v, vec![1; 100]);
+            n_found += 1;
+        }
+    }
+    assert_eq!(n_found, fh.len());
+}
+
+#[test]
+fn test_repair_deleted() {
+    let main = Arc::new(MemIo::new());
+    let overflow = Arc::new(MemIo::new());
+    let options = Options {
+        hasher: Arc::new(Xxh3),
+        ..Default::default()
+    };
+
+    let mut fh = ForeverHash::open_with_stores(
+        Box::new(main.clone()),
+        Box::new(overflow.clone()),
+        options.clone(),
+    )
+    .unwrap();
+    let n = 5000;
+    for i in 0..n {
+        fh.insert(vec(i), vec![0; 100]).unwrap();
+    }
+    for i in 100..n {
+        fh.delete(&vec(i)).unwrap();
+    }
+    // Not closed, so the freed overflow pages with the deleted pairs are orphaned.
+    std::mem::forget(fh);
+
+    let dst_main = Arc::new(MemIo::new());
+    let dst_overflow = Arc::new(MemIo::new());
+    let report = ForeverHash::repair_stores(
+        Box::new(main.clone()),
+        Box::new(overflow.clone()),
+        Box::new(dst_main.clone()),
+        Box::new(dst_overflow.clone()),
+        options.clone(),
+    )
+    .unwrap();
+    assert_eq!(report.lost, vec![]);
+    assert_eq!(report.n_orphaned_items, 0);
+    assert_eq!(report.n_items, 100);
+
+    let fh =
+        ForeverHash::open_with_stores(Box::new(dst_main), Box::new(dst_overflow), options).unwrap();
+    for i in 0..n {
+        assert_eq!(fh.get(&vec(i)).unwrap().is_some(), i < 100);
+    }
+}
+
+#[test]
+fn test_write_batch() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let mut fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+
+    let n = 10000;
+    for chunk in (0..n).collect::<Vec<_>>().chunks(1000) {
+        let mut batch = WriteBatch::new();
+        for &i in chunk {
+            batch.put(vec(i), vec(i));
+        }
+        fh.write_batch(batch).unwrap();
+    }
+    assert_eq!(fh.len(), n);
+
+    let mut batch = WriteBatch::new();
+    for i in 0..n {
+        match i % 3 {
+            0 => batch.put(vec(i), vec(i + 1)),
+            1 => batch.delete(vec(i)),
+            // The last write wins.
+            _ => {
+                batch.delete(vec(i));
+                batch.put(vec(i), vec(i + 2));
+            }
+        }
+    }
+    fh.write_batch(batch).unwrap();
+    // Mixed with single writes.
+    fh.insert(vec(n), vec(n)).unwrap();
+    fh.delete(&vec(n)).unwrap();
+
+    let check = |fh: &ForeverHash| {
+        assert_eq!(fh.len(), n - n / 3);
+        for i in 0..n {
+            let expected = match i % 3 {
+                0 => Some(vec(i + 1)),
+                1 => None,
+                _ => Some(vec(i + 2)),
+            };
+            assert_eq!(fh.get(&vec(i)).unwrap(), expected);
+        }
+    };
+    check(&fh);
+    fh.close().unwrap();
+
+    let mut fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+    check(&fh);
+
+    let mut too_large = WriteBatch::new();
+    too_large.put(vec(0), vec![0; 8192]);
+    assert!(matches!(fh.write_batch(too_large), Err(Error::TooLarge)));
+    check(&fh);
+
+    // A batch is committed with one flush of each file.
+    let main = Arc::new(FaultyIo::new(MemIo::new()));
+    let overflow = Arc::new(FaultyIo::new(MemIo::new()));
+    let mut fh = ForeverHash::open_with_stores(
+        Box::new(main.clone()),
+        Box::new(overflow.clone()),
+        Options::default(),
+    )
+    .unwrap();
+    for i in 0..100 {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    fh.sync().unwrap();
+    let n_flushes = || main.n_flushes() + overflow.n_flushes();
+
+    let mut batch = WriteBatch::new();
+    for i in 0..50 {
+        batch.put(vec(i), vec(i + 1));
+    }
+    let before = n_flushes();
+    fh.write_batch(batch.clone()).unwrap();
+    assert_eq!(n_flushes() - before, 2);
+
+    // The journal is kept until the pages are flushed. A write to another key doesn't wait for it.
+    let before = n_flushes();
+    fh.insert(vec(60), vec(0)).unwrap();
+    assert_eq!(n_flushes() - before, 0);
+    // A write to a key of the batch flushes the pages and drops the journal first, once.
+    fh.insert(vec(10), vec(0)).unwrap();
+    fh.insert(vec(11), vec(0)).unwrap();
+    assert_eq!(n_flushes() - before, 2);
+
+    let before = n_flushes();
+    fh.write_batch(batch).unwrap();
+    assert_eq!(n_flushes() - before, 2);
+    for i in 0..50 {
+        assert_eq!(fh.get(&vec(i)).unwrap(), Some(vec(i + 1)));
+    }
+
+    // Only the pages changed are rewritten, even in a long chain.
+    // The keys are multiples of 2^20 so they all go to the first bucket.
+    for i in 0..200 {
+        fh.insert(vec(i << 20), vec![0; 100]).unwrap();
+    }
+    assert!(fh.stats().unwrap().chain_lengths.len() > 5);
+    fh.sync().unwrap();
+    let n_writes = || main.n_writes() + overflow.n_writes();
+    let before = n_writes();
+    let mut batch = WriteBatch::new();
+    batch.put(vec(100 << 20), vec![1; 100]);
+    fh.write_batch(batch).unwrap();
+    // The journal, the superblock and the page holding the key.
+    assert_eq!(n_writes() - before, 3);
+    assert_eq!(fh.get(&vec(100 << 20)).unwrap(), Some(vec![1; 100]));
+}
+
+#[test]
+fn test_shared() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let options = Options {
+        page_size: 1024,
+        cache_size: 64 * 1024,
+        ..Default::default()
+    };
+    let fh = ForeverHash::open_with(main.path(), overflow.path(), options).unwrap();
+    let fh = Arc::new(SharedForeverHash::new(fh));
+
+    let n = 3000;
+    // Keys below `inserted` and not below `deleted` must be found.
+    let inserted = Arc::new(AtomicU64::new(0));
+    let deleted = Arc::new(AtomicU64::new(0));
+
+    let readers: Vec<_> = (0..4)
+        .map(|t| {
+            let fh = fh.clone();
+            let inserted = inserted.clone();
+            let deleted = deleted.clone();
+            std::thread::spawn(move || {
+                let mut i = t;
+                while deleted.load(Ordering::Acquire) < n {
+                    let lo = deleted.load(Ordering::Acquire);
+                    let hi = inserted.load(Ordering::Acquire);
+                    if lo < hi {
+                        let k = lo + i % (hi - lo);
+                        match fh.get(&vec(k)).unwrap() {
+                            Some(v) => assert_eq!(v, vec(k)),
+                            // Deleted after `lo` was read.
+                            None => assert!(k < deleted.load(Ordering::Acquire)),
+                        }
+                    }
+                    i += 7;
+                }
+            })
+        })
+        .collect();
+
+    // Splits while inserting and merges while deleting.
+    for i in 0..n {
+        fh.insert(vec(i), vec(i)).unwrap();
+        inserted.store(i + 1, Ordering::Release);
+    }
+    assert_eq!(fh.lock().iter().count() as u64, n);
+    for i in 0..n {
+        deleted.store(i + 1, Ordering::Release);
+        fh.delete(&vec(i)).unwrap();
+    }
+
+    for r in readers {
+        r.join().unwrap();
+    }
+    assert!(fh.is_empty());
+    Arc::into_inner(fh).unwrap().close().unwrap();
+}
+
+#[test]
+fn test_sync_policy() {
+    let open = |sync_policy| {
+        let main = Arc::new(FaultyIo::new(MemIo::new()));
+        let overflow = Arc::new(FaultyIo::new(MemIo::new()));
+        let options = Options {
+            sync_policy,
+            ..Default::default()
+        };
+        let fh = ForeverHash::open_with_stores(
+            Box::new(main.clone()),
+            Box::new(overflow.clone()),
+            options,
+        )
+        .unwrap();
+        (fh, main, overflow)
+    };
+    let fail_flush = |main: &FaultyIo<MemIo>, overflow: &FaultyIo<MemIo>, fail| {
+        main.fail_flush(fail);
+        overflow.fail_flush(fail);
+    };
+
+    // Nothing is flushed even on splits until `sync`.
+    let (mut fh, main, overflow) = open(SyncPolicy::Never);
+    fail_flush(&main, &overflow, true);
+    for i in 0..10000 {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    assert!(fh.sync().is_err());
+    fail_flush(&main, &overflow, false);
+    fh.sync().unwrap();
+
+    // In-place updates are not flushed.
+    let (mut fh, main, overflow) = open(SyncPolicy::OnSplit);
+    fh.insert(vec(0), vec(0)).unwrap();
+    fail_flush(&main, &overflow, true);
+    fh.insert(vec(0), vec(1)).unwrap();
+
+    let (mut fh, main, overflow) = open(SyncPolicy::Interval(Duration::from_secs(3600)));
+    fh.insert(vec(0), vec(0)).unwrap();
+    fail_flush(&main, &overflow, true);
+    fh.insert(vec(0), vec(1)).unwrap();
+
+    for policy in [
+        SyncPolicy::EveryWrite,
+        SyncPolicy::GroupCommit,
+        SyncPolicy::Interval(Duration::ZERO),
+    ] {
+        let (mut fh, main, overflow) = open(policy);
+        fh.insert(vec(0), vec(0)).unwrap();
+        fail_flush(&main, &overflow, true);
+        assert!(fh.insert(vec(0), vec(1)).is_err());
+        assert!(fh.delete(&vec(0)).is_err());
+    }
+
+    // An in-place update is flushed once per file.
+    let (mut fh, main, overflow) = open(SyncPolicy::EveryWrite);
+    fh.insert(vec(0), vec(0)).unwrap();
+    let n_flushes = || main.n_flushes() + overflow.n_flushes();
+    let before = n_flushes();
+    for i in 0..100 {
+        fh.insert(vec(0), vec(i)).unwrap();
+    }
+    assert_eq!(n_flushes() - before, 200);
+}
+
+/// A store whose flushes are slow, so the writers pile up behind a flush.
+struct SlowFlushIo(FaultyIo<MemIo>);
+
+impl BlockIo for SlowFlushIo {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        self.0.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+        self.0.write_at(buf, offset)
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        std::thread::sleep(Duration::from_millis(1));
+        self.0.flush()
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        self.0.len()
+    }
+
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        self.0.set_len(len)
+    }
+}
+
+#[test]
+fn test_group_commit() {
+    let main = Arc::new(SlowFlushIo(FaultyIo::new(MemIo::new())));
+    let overflow = Arc::new(SlowFlushIo(FaultyIo::new(MemIo::new())));
+    let options = Options {
+        sync_policy: SyncPolicy::GroupCommit,
+        ..Default::default()
+    };
+    let fh = ForeverHash::open_with_stores(
+        Box::new(main.clone()),
+        Box::new(overflow.clone()),
+        options.clone(),
+    )
+    .unwrap();
+    let fh = Arc::new(SharedForeverHash::new(fh));
+    let n_flushes = || main.0.n_flushes() + overflow.0.n_flushes();
+    let before = n_flushes();
+
+    let n = 400;
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let fh = fh.clone();
+            std::thread::spawn(move || {
+                for i in (t..n).step_by(4) {
+                    fh.insert(vec(i), vec(i)).unwrap();
+                }
+            })
+        })
+        .collect();
+    for w in writers {
+        w.join().unwrap();
+    }
+    assert_eq!(fh.len(), n);
+    // `EveryWrite` flushes both files after each write. The writers waiting together share the flushes.
+    let n_flushes = n_flushes() - before;
+    assert!(n_flushes < n, "{n_flushes}");
+    Arc::into_inner(fh).unwrap().close().unwrap();
+
+    let fh = ForeverHash::open_with_stores(Box::new(main), Box::new(overflow), options).unwrap();
+    for i in 0..n {
+        assert_eq!(fh.get(&vec(i)).unwrap(), Some(vec(i)));
+    }
+}
+
+#[test]
+fn test_wal() {
+    let main = Arc::new(FaultyIo::new(MemIo::new()));
+    let overflow = Arc::new(FaultyIo::new(MemIo::new()));
+    let wal = Arc::new(MemIo::new());
+    let open = || {
+        let options = Options {
+            wal: true,
+            ..Default::default()
+        };
+        ForeverHash::open_with_wal_stores(
+            Box::new(main.clone()),
+            Box::new(overflow.clone()),
+            Box::new(wal.clone()),
+            options,
+        )
+        .unwrap()
+    };
+
+    let mut fh = open();
+    let n = 1000;
+    for i in 0..n {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    fh.delete(&vec(1)).unwrap();
+    // Checkpointed by sync.
+    fh.sync().unwrap();
+    assert_eq!(wal.len().unwrap(), 0);
+
+    // The pages are torn after the mutations are logged.
+    fh.insert(vec(2), vec(n)).unwrap();
+    main.tear_writes(100);
+    main.fail_writes_after(0);
+    overflow.fail_writes_after(0);
+    assert!(fh.insert(vec(0), vec(n)).is_err());
+    assert!(fh.delete(&vec(3)).is_err());
+    assert!(fh.insert(vec(n), vec(n)).is_err());
+    std::mem::forget(fh);
+    main.heal();
+    overflow.heal();
+
+    let fh = open();
+    assert_eq!(wal.len().unwrap(), 0);
+    assert_eq!(fh.len(), n - 1);
+    assert_eq!(fh.get(&vec(0)).unwrap(), Some(vec(n)));
+    assert_eq!(fh.get(&vec(1)).unwrap(), None);
+    assert_eq!(fh.get(&vec(2)).unwrap(), Some(vec(n)));
+    assert_eq!(fh.get(&vec(3)).unwrap(), None);
+    assert_eq!(fh.get(&vec(n)).unwrap(), Some(vec(n)));
+
+    let options = Options {
+        wal: true,
+        ..Default::default()
+    };
+    assert!(
+        ForeverHash::open_with_stores(Box::new(MemIo::new()), Box::new(MemIo::new()), options)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_snapshot() {
+    check_snapshot(Options {
+        page_size: 1024,
+        cache_size: 64 * 1024,
+        ..Default::default()
+    });
+    // The pages read from the mapping must not be truncated by merges under the reader.
+    check_snapshot(Options {
+        page_size: 1024,
+        mmap: true,
+        ..Default::default()
+    });
+}
+
+fn check_snapshot(options: Options) {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let mut fh = ForeverHash::open_with(main.path(), overflow.path(), options).unwrap();
+
+    let n = 2000;
+    for i in 0..n {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    let snap = Arc::new(fh.snapshot());
+
+    // Read the snapshot while the table is updated, shrunk by merges and grown by splits.
+    let reader = {
+        let snap = snap.clone();
+        std::thread::spawn(move || {
+            for _ in 0..3 {
+                let mut pairs: Vec<_> = snap.iter().map(|kv| kv.unwrap()).collect();
+                pairs.sort();
+                let mut expected: Vec<_> = (0..n).map(|i| (vec(i), vec(i))).collect();
+                expected.sort();
+                assert_eq!(pairs.len(), expected.len());
+                for (a, b) in pairs.iter().zip(&expected) {
+                    assert_eq!(a, b);
+                }
+            }
+        })
+    };
+    for i in (0..n).step_by(2) {
+        fh.insert(vec(i), vec(i + 1)).unwrap();
+    }
+    for i in 0..n - 100 {
+        fh.delete(&vec(i)).unwrap();
+    }
+    for i in n..3 * n {
+        fh.insert(vec(i), vec(i)).unwrap();
+    }
+    reader.join().unwrap();
+
+    assert_eq!(snap.len(), n);
+    for i in 0..3 * n {
+        let expected = (i < n).then(|| vec(i));
+        assert_eq!(snap.get(&vec(i)).unwrap(), expected);
+    }
+
+    // A later snapshot sees the later writes.
+    let snap2 = fh.snapshot();
+    assert_eq!(snap2.len(), 2 * n + 100);
+    assert_eq!(snap2.get(&vec(n)).unwrap(), Some(vec(n)));
+    assert_eq!(snap2.get(&vec(0)).unwrap(), None);
+
+    // The snapshot outlives the table.
+    fh.close().unwrap();
+    assert_eq!(snap.iter().count() as u64, n);
+    assert_eq!(snap2.iter().count() as u64, 2 * n + 100);
+}
+
+#[test]
+fn test_conditional() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let mut fh = ForeverHash::open(main.path(), overflow.path()).unwrap();
+
+    let n = 3000;
+    for i in 0..n {
+        assert!(fh.insert_if_absent(vec(i), vec(i)).unwrap());
+    }
+    for i in 0..n {
+        assert!(!fh.insert_if_absent(vec(i), vec(i + 1)).unwrap());
+    }
+    assert_eq!(fh.len(), n);
+
+    for i in 0..n {
+        assert!(
+            !fh.compare_and_swap(vec(i), Some(&vec(i + 1)), vec(0))
+                .unwrap()
+        );
+        assert!(!fh.compare_and_swap(vec(i), None, vec(0)).unwrap());
+        assert!(
+            fh.compare_and_swap(vec(i), Some(&vec(i)), vec(i + 1))
+                .unwrap()
+        );
+    }
+    assert!(fh.compare_and_swap(vec(n), None, vec(n + 1)).unwrap());
+
+    // Grow the values so some pairs move to other pages of the chain.
+    for i in 0..=n {
+        let old = fh
+            .update(vec(i), |old| {
+                let mut v = old.unwrap().to_vec();
+                v.extend_from_slice(&vec(i));
+                Some(v)
+            })
+            .unwrap();
+        assert_eq!(old, Some(vec(i + 1)));
+    }
+    assert_eq!(fh.update(vec(n + 1), |_| None).unwrap(), None);
+    assert_eq!(fh.update(vec(n), |_| None).unwrap().unwrap().len(), 16);
+    assert_eq!(fh.len(), n);
+
+    for i in 0..n {
+        let removed = fh.remove_if(&vec(i), |v| v[..8] == vec(i + 1)[..] && i % 2 == 0);
+        assert_eq!(removed.unwrap().is_some(), i % 2 == 0);
+    }
+    assert_eq!(fh.len(), n / 2);
+    for i in 0..n {
+        let v = fh.get(&vec(i)).unwrap();
+        if i % 2 == 0 {
+            assert_eq!(v, None);
+        } else {
+            assert_eq!(v, Some([vec(i + 1), vec(i)].concat()));
+        }
+    }
+    assert_eq!(fh.iter().count() as u64, n / 2);
+
+    // Concurrent increments don't lose updates.
+    let fh = Arc::new(SharedForeverHash::new(fh));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let fh = fh.clone();
+            std::thread::spawn(move || {
+                for _ in 0..500 {
+                    fh.update(b"counter".to_vec(), |old| {
+                        let c = old.map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+                        Some(vec(c + 1))
+                    })
+                    .unwrap();
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(fh.get(b"counter").unwrap(), Some(vec(2000)));
+    Arc::into_inner(fh).unwrap().close().unwrap();
+}
+
+#[test]
+fn test_get_many() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let options = Options {
+        page_size: 1024,
+        ..Default::default()
+    };
+    let mut fh = ForeverHash::open_with(main.path(), overflow.path(), options).unwrap();
+
+    let n = 5000;
+    // Long values so the chains have overflow pages.
+    for i in 0..n {
+        fh.insert(vec(i), vec![i as u8; 100]).unwrap();
+    }
+
+    // Missing and repeated keys in random order.
+    let keys: Vec<Vec<u8>> = (0..n + 500)
+        .rev()
+        .step_by(3)
+        .map(vec)
+        .chain([vec(1), vec(1)])
+        .collect();
+    let values = fh.get_many(&keys).unwrap();
+    assert_eq!(values.len(), keys.len());
+    for (k, v) in keys.iter().zip(&values) {
+        assert_eq!(*v, fh.get(k).unwrap());
+    }
+    assert!(values.iter().any(|v| v.is_none()));
+}
+
+#[test]
+fn test_stats() {
+    let main = tempfile::NamedTempFile::new().unwrap();
+    let overflow = tempfile::NamedTempFile::new().unwrap();
+    let options = Options {
+        page_size: 1024,
+        ..Default::default()
+    };
+    let mut fh = ForeverHash::open_with(main.path(), overflow.path(), options.clone()).unwrap();
+
+    let stats = fh.stats().unwrap();
+    assert_eq!(stats.n_items, 0);
+    assert_eq!(stats.n_main_pages, 2);
+    assert_eq!(stats.chain_lengths, [2]);
+
+    let n = 5000;
+    for i in 0..n {
+        fh.insert(vec(i), vec![0; 100]).unwrap();
+    }
+    for i in 0..n / 2 {
+        fh.delete(&vec(i)).unwrap();
+    }
+
+    let stats = fh.stats().unwrap();
+    assert_eq!(stats.n_items, n / 2);
+    assert_eq!(
+        stats.n_main_pages,
+        (1 << stats.main_base_level) + stats.next_split_main_page_id
+    );
+    assert_eq!(stats.chain_lengths.iter().sum::<u64>(), stats.n_main_pages);
+    let n_chained: u64 = (0..).zip(&stats.chain_lengths).map(|(n, c)| n * c).sum();
+    assert_eq!(n_chained, stats.n_used_overflow_pages);
+    assert!(stats.max_chain_length() > 0);
+    assert_eq!(stats.n_leaked_overflow_pages, 0);
+    assert_eq!(
+        stats.n_overflow_pages,
+        stats.n_used_overflow_pages + stats.n_free_overflow_pages
+    );
+    assert!(stats.bytes_used > n / 2 * 100);
+    assert!(stats.avg_page_fill() > 0.0 && stats.avg_page_fill() <= 1.0);
+    assert!(stats.load_factor > 0.3 && stats.load_factor <= 0.8);
+
+    // Degenerate keys all in one bucket make one long chain.
+    for i in 0..200 {
+        fh.insert(vec(i << 32), vec![0; 100]).unwrap();
+    }
+    let skewed = fh.stats().unwrap();
+    assert!(skewed.max_chain_length() > stats.max_chain_length() + 10);
+    assert_eq!(skewed.chain_lengths.last(), Some(&1));
+
+    fh.close().unwrap();
+    let report = ForeverHash::verify(main.path(), overflow.path(), options).unwrap();
+    assert_eq!(report.n_used_overflow_pages, skewed.n_used_overflow_pages);
+}
+
+#[test]
+fn test_filter() {
+    let main = Arc::new(MemIo::new());
+    let overflow = Arc::new(FaultyIo::new(MemIo::new()));
+    let options = Options {
+        page_size: 1024,
+        filter_len: 128,
+        ..Default::default()
+    };
+    let open = |options: &Options| {
+        ForeverHash::open_with_stores(
+            Box::new(main.clone()),
+            Box::new(overflow.clone()),
+            options.clone(),
+        )
+        .unwrap()
+    };
+    let mut fh = open(&options);
+
+    let n = 3000;
+    for i in 0..n {
+        fh.insert(vec(i), vec![i as u8; 100]).unwrap();
+    }
+    for i in (0..n).step_by(2) {
+        fh.delete(&vec(i)).unwrap();
+    }
+    for i in (1..n).step_by(4) {
+        fh.insert(vec(i), vec![0; 50]).unwrap();
+    }
+    assert!(fh.stats().unwrap().n_used_overflow_pages > 0);
+
+    for i in 0..n {
+        let expected = match i % 4 {
+            1 => Some(vec![0; 50]),
+            3 => Some(vec![i as u8; 100]),
+            _ => None,
+        };
+        assert_eq!(fh.get(&vec(i)).unwrap(), expected);
+    }
+
+    // Keys in the same bucket make a long chain.
+    for i in 0..100 {
+        fh.insert(vec(i << 32), vec![0; 100]).unwrap();
+    }
+    assert!(fh.stats().unwrap().max_chain_length() > 10);
+
+    // Most misses stop at the main page without reading the overflow pages.
+    overflow.fail_reads(true);
+    let n_errors = (100..1100)
+        .filter(|&i| fh.get(&vec(i << 32)).is_err())
+        .count();
+    assert!(n_errors < 100, "{n_errors}");
+    overflow.heal();
+
+    fh.close().unwrap();
+    let report = ForeverHash::verify_stores(
+        Box::new(main.clone()),
+        Box::new(overflow.clone()),
+        options.clone(),
+    )
+    .unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+
+    // The filters are kept up to date even if they are disabled on open.
+    let mut fh = open(&Options {
+        filter_len: 0,
+        ..options.clone()
+    });
+    for i in n..n + 500 {
+        fh.insert(vec(i), vec![1; 100]).unwrap();
+    }
+    for i in n..n + 500 {
+        assert_eq!(fh.get(&vec(i)).unwrap(), Some(vec![1; 100]));
+    }
+    fh.close().unwrap();
+    let report = ForeverHash::verify_stores(
+        Box::new(main.clone()),
+        Box::new(overflow.clone()),
+        options.clone(),
+    )
+    .unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+
+    let too_large = Options {
+        filter_len: 512,
+        ..options
+    };
+    assert!(matches!(
+        ForeverHash::open_with_stores(Box::new(MemIo::new()), Box::new(MemIo::new()), too_large),
+        Err(Error::InvalidFilterLen(512))
+    ));
+}